@@ -1,31 +1,26 @@
-use log::{error, info};
+use log::info;
 use std::thread;
 use std::sync::{Arc, Mutex};
 use std::error::Error;
 use std::time::Instant;
-use chrono::{NaiveTime, NaiveDateTime};
+use std::ops::RangeInclusive;
+use chrono::NaiveDate;
 use simple_error::SimpleError;
 pub use crate::strategy::*;
 pub use crate::utils::*;
-pub use crate::vector_utils::*;
+use crate::window::{all_days, CellTime, WindowEngine};
+use crate::metrics::Observations;
+use crate::exits::{simulate_exit, Exit, ExitRule};
+use crate::contracts::ContractSpec;
 
 
-pub fn run_analysis(datetimes: &Vec<NaiveDateTime>, values: &Vec<f64>,
-                    interval_rng: &Vec<u64>, start_time_rng: &Vec<NaiveTime>,
-                    progress_counter: Arc<Mutex<u64>>, total_runs: u64, context_conditions: &Vec<Vec<bool>>)
+pub fn run_analysis(engine: &WindowEngine, interval_rng: &[u64], start_time_rng: &[CellTime],
+                    spec: &StrategySpec, progress_counter: Arc<Mutex<u64>>, total_runs: u64)
                     -> Result<Vec<StrategyResult>, Box<dyn Error>> {
     let thread_name = match thread::current().name() {
         Some(x) => String::from(x),
         None => String::from("no thread???")
     };
-
-    let mut context_condition = vec![true; values.len()];
-    if !context_conditions.is_empty() {
-        for c in context_conditions {
-            context_condition = context_condition.iter().zip(c.iter()).map(|(x, y)| x & y).collect();
-        }
-    }
-
     let mut ret: Vec<StrategyResult> = Vec::new();
     let now = Instant::now();
     for interval in interval_rng {
@@ -33,100 +28,170 @@ pub fn run_analysis(datetimes: &Vec<NaiveDateTime>, values: &Vec<f64>,
             {
                 let mut p = progress_counter.lock().unwrap();
                 *p += 1;
-                if *p % 500 == 0 {
+                if (*p).is_multiple_of(500) {
                     let elapsed = now.elapsed().as_secs_f32();
                     let pct = (*p as f32)/(total_runs as f32);
                     info!("Iteration {} ({:.1}%) out of {} on thread {}, {:.1}s elapsed  (total {:.0}s expected)",
-                             *p, pct*100., total_runs, thread_name, elapsed, (elapsed as f32)/pct);
+                             *p, pct*100., total_runs, thread_name, elapsed, elapsed/pct);
                 }
             }
-            let end_time = add_time(start_time, interval*60);
-            if end_time >= NaiveTime::from_hms(17,0,0) { continue; } // End of day for futures
-
-            // Set entry condition
-            let mut entry_cond: Vec<bool> = datetimes.iter().map(|x| x.time()==*start_time).collect(); // absolute time strat
-
-            // Set exit condition
-            let mut exit_cond: Vec<bool> = datetimes.iter().map(|x| x.time()==end_time).collect();
-
-            entry_cond = entry_cond.iter().zip(&context_condition).map(|(x,y)| x&y).collect();
-            exit_cond = exit_cond.iter().zip(&context_condition).map(|(x,y)| x&y).collect();
+            ret.extend(analyse_cell(engine, *interval, start_time, spec, &all_days()));
+        }
+    }
 
-            // Set r as total condition vector
-            let r:Vec<i32> = entry_cond.iter().zip(exit_cond.iter())
-                .map(|(&x, &y)| (x as i32) - (y as i32))
-                .collect();
+    match ret.len()
+    {
+        0 => Err(Box::new(SimpleError::new(format!("Returns on thread {} length was zero", thread_name)))),
+        _ => Ok(ret)
+    }
+}
 
-            let r: Vec<usize> = fill_ids(&r);
+/// Results for every exit rule and direction of one interval/start time cell, trading only on
+/// `days`. Cells need at least two trades with some dispersion in their returns to be reported.
+pub fn analyse_cell(engine: &WindowEngine, interval: u64, start_time: &CellTime, spec: &StrategySpec,
+                    days: &RangeInclusive<NaiveDate>) -> Vec<StrategyResult>
+{
+    let end_time = match window_end_time(start_time, interval, &spec.contract) {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+    let (directions, exit_rules) = (&spec.directions, &spec.exit_rules);
+    let obs = cell_observations(engine, start_time, &end_time, directions, exit_rules, spec, days);
+    let cells = exit_rules.iter().flat_map(|rule| directions.iter().map(move |direction| (rule, direction)));
+    cells.zip(obs.iter())
+        .filter(|(_, (obs, _))| {
+            let sharpe = vec_mean(&obs.returns).unwrap_or(f64::NAN) / vec_std(&obs.returns).unwrap_or(f64::NAN);
+            sharpe.is_normal() && obs.len() >= 2
+        })
+        .map(|((rule, direction), (obs, net_obs))| StrategyResult {
+            interval,
+            start_time: *start_time,
+            end_time,
+            direction: *direction,
+            exit_rule: *rule,
+            metrics: [spec.compute_metrics(obs, net_obs),
+                      spec.confidence_intervals(obs, net_obs, (interval, start_time, direction, rule.to_string()))]
+                .concat(),
+            n_obs: obs.len(),
+        })
+        .collect()
+}
 
-            let mut returns: Vec<f64> = Vec::new();
-            let mut drawups: Vec<f64> = Vec::new();
-            let mut drawdowns: Vec<f64> = Vec::new();
-            // let mut datetime_data: Vec<Vec<NaiveDateTime>> = Vec::new();
-            // let mut value_data: Vec<Vec<f64>> = Vec::new();
-            let mut n_obs= 0_usize;
-            for i in vec_unique(&r).into_iter()
+/// Gross and net of cost observations of the windows from `start_time` to `end_time` on `days`, for
+/// each exit rule and direction pair, rule major. Net observations stay empty without a cost model.
+pub fn cell_observations(engine: &WindowEngine, start_time: &CellTime, end_time: &CellTime,
+                         directions: &[Direction], exit_rules: &[ExitRule], spec: &StrategySpec,
+                         days: &RangeInclusive<NaiveDate>) -> Vec<(Observations, Observations)>
+{
+    let scale = spec.unit_scale();
+    let mut obs: Vec<(Observations, Observations)> = (0..directions.len() * exit_rules.len())
+        .map(|_| (Observations::default(), Observations::default()))
+        .collect();
+    for (entry_ix, exit_ix) in cell_windows(engine, start_time, end_time, days, spec)
+    {
+        if exit_ix - entry_ix < 2 { continue }
+        let sides: Vec<Option<f64>> = directions.iter().map(|d| d.side(&engine.bars, entry_ix, spec.fill)).collect();
+        let entry_price = spec.fill.price(&engine.bars, entry_ix);
+        for (r, rule) in exit_rules.iter().enumerate()
+        {
+            for (d, side) in sides.iter().enumerate()
             {
-                if i == &0 { continue; } // 0 means no observation
-                let ix = vec_where_eq(&r, i);
-                // let t = datetimes[ix[0]..=ix[ix.len()-1]].to_vec();
-                let v:Vec<f64> = values[ix[0]..=ix[ix.len()-1]].to_vec();
-
-                if v.len() > 2
+                let side = match side {
+                    Some(x) => *x,
+                    None => continue,
+                };
+                let exit = simulate_exit(engine, entry_ix, exit_ix, side, rule, spec.fill, spec.contract.tick_size);
+                let ret = (exit.price - entry_price) * side * scale;
+                let (low, high) = excursions(engine, entry_ix, &exit, spec.fill, scale);
+                let (drawup, drawdown) = if side > 0. { (low, high) } else { (-high, -low) };
+                let (obs, net_obs) = &mut obs[r*directions.len() + d];
+                obs.push(ret, drawup, drawdown);
+                if spec.costs.is_some()
                 {
-                    n_obs += 1;
-                    let mut d = vec_diff(&v, 1).unwrap();
-                    d = vec_cumsum(&d).unwrap();
-                    d.sort_by(|a, b| comp_f64(a,b));
-                    returns.push(v[v.len()-1] - v[0]);
-                    drawups.push(d[0]);
-                    drawdowns.push(d[d.len() - 1]);
-                    // datetime_data.push(t);
-                    // value_data.push(v);
+                    let cost = spec.round_trip_cost(engine, entry_ix, exit.ix) * scale;
+                    net_obs.push(ret - cost, drawup - cost, drawdown - cost);
                 }
             }
-            let sharpe = vec_mean(&returns).unwrap_or(f64::NAN) / vec_std(&returns).unwrap_or(f64::NAN);
-            if !sharpe.is_normal() { continue }
-
-            let ann_factor = (252_f64).sqrt();
-            drawups.sort_by(|a, b| comp_f64(a,b));
-            drawdowns.sort_by(|a, b| comp_f64(a,b));
-
-            let max_drawup = match drawups.get(1)
-            {
-                Some(x) => x,
-                None => continue
-            };
-            let max_drawdown = match drawdowns.get(drawdowns.len() - 1)
-            {
-                Some(x) => x,
-                None => continue
-            };
-
-            ret.push(StrategyResult
-                {
-                    interval: *interval,
-                    start_time: *start_time,
-                    end_time: end_time,
-                    sharpe: sharpe*ann_factor,
-                    max_drawup: *max_drawup,
-                    max_drawdown: *max_drawdown,
-                    n_obs,
-                    // datetime_data,
-                    // value_data,
-                }
-            );
         }
     }
+    obs
+}
 
-    match ret.len()
-    {
-        0 => {
-            let msg = format!("Returns on thread {} length was zero", thread_name);
-            error!("{}", msg);
-            Err(Box::new(SimpleError::new(msg)))
+/// Exit time of a window, or `None` when a clock window would start outside the session, reach the
+/// session close or wrap past midnight. Release-relative windows are checked against the session one
+/// by one.
+pub fn window_end_time(start_time: &CellTime, interval: u64, contract: &ContractSpec) -> Option<CellTime>
+{
+    match start_time {
+        CellTime::Clock(start_time) => {
+            let end_time = add_time(start_time, interval*60);
+            if !contract.in_session(start_time) || end_time >= contract.session_close || end_time <= *start_time {
+                return None
+            }
+            Some(CellTime::Clock(end_time))
         },
-        _ => Ok(ret)
+        CellTime::Release(m) => Some(CellTime::Release(m + interval as i64)),
     }
 }
 
+/// Windows of a cell on `days`, dropping any entered outside the session or whose exit reaches the
+/// session close, or the early close on half days. Clock windows are always within the regular session.
+fn cell_windows<'a>(engine: &'a WindowEngine, start_time: &'a CellTime, end_time: &'a CellTime,
+                    days: &RangeInclusive<NaiveDate>, spec: &'a StrategySpec) -> impl Iterator<Item = (usize, usize)> + 'a
+{
+    let session_close = spec.contract.session_close;
+    engine.windows_between(start_time, end_time, days)
+        .filter(move |&(entry_ix, exit_ix)| {
+            let exit = engine.bars.datetime(exit_ix);
+            spec.contract.in_session(&engine.bars.datetime(entry_ix).time()) && exit.time() < spec.calendar.early_close(exit.date()).map_or(session_close, |x| x.min(session_close))
+        })
+}
+
+/// Trade ledger for a single interval/start time pair, using the same windows and exits as
+/// `run_analysis`
+pub fn run_trades(engine: &WindowEngine, interval: u64, start_time: &CellTime, direction: Direction,
+                  exit_rule: &ExitRule, spec: &StrategySpec) -> Vec<Trade>
+{
+    let end_time = match window_end_time(start_time, interval, &spec.contract) {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+
+    let bars = &engine.bars;
+    let scale = spec.unit_scale();
+    cell_windows(engine, start_time, &end_time, &all_days(), spec)
+        .filter(|(entry_ix, exit_ix)| exit_ix - entry_ix >= 2)
+        .filter_map(|(entry_ix, exit_ix)| {
+            let side = direction.side(bars, entry_ix, spec.fill)?;
+            let entry_price = spec.fill.price(bars, entry_ix);
+            let exit = simulate_exit(engine, entry_ix, exit_ix, side, exit_rule, spec.fill, spec.contract.tick_size);
+            let (low, high) = excursions(engine, entry_ix, &exit, spec.fill, scale);
+            let ret = (exit.price - entry_price) * side * scale;
+            let cost = spec.round_trip_cost(engine, entry_ix, exit.ix) * scale;
+            Some(Trade {
+                entry_datetime: bars.datetime(entry_ix),
+                exit_datetime: bars.datetime(exit.ix),
+                side,
+                entry_price,
+                exit_price: exit.price,
+                exit_reason: exit.reason,
+                ret,
+                cost,
+                net_ret: ret - cost,
+                mfe: if side > 0. { high } else { -low },
+                mae: if side > 0. { low } else { -high },
+                bars_held: exit.ix - entry_ix,
+            })
+        })
+        .collect()
+}
+
+/// Lowest low and highest high relative to the entry price over the bars a trade was exposed to,
+/// multiplied by `scale`
+fn excursions(engine: &WindowEngine, entry_ix: usize, exit: &Exit, fill: Fill, scale: f64) -> (f64, f64)
+{
+    let entry_price = fill.price(&engine.bars, entry_ix);
+    let (first, _) = fill.held_bars(entry_ix, exit.ix);
+    ((engine.range_low(first, exit.last_bar) - entry_price) * scale,
+     (engine.range_high(first, exit.last_bar) - entry_price) * scale)
+}
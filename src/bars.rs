@@ -0,0 +1,310 @@
+use std::ops::{Deref, Range, RangeInclusive};
+use std::sync::Arc;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use chrono_tz::Tz;
+use log::warn;
+use memmap2::Mmap;
+use crate::timezones::convert;
+
+/// Seconds since 1970-01-01 00:00 of a wall clock time
+pub fn to_timestamp(dt: &NaiveDateTime) -> i64
+{
+    dt.and_utc().timestamp()
+}
+
+/// Wall clock time `timestamp` seconds after 1970-01-01 00:00
+pub fn from_timestamp(timestamp: i64) -> NaiveDateTime
+{
+    DateTime::from_timestamp(timestamp, 0).expect("timestamp out of range").naive_utc()
+}
+
+/// One column of bar data, owned or borrowed from a memory-mapped file
+#[derive(Debug, Clone)]
+pub(crate) enum ColumnData<T>
+{
+    Owned(Vec<T>),
+    Mapped { map: Arc<Mmap>, offset: usize, len: usize },
+}
+
+impl<T: bytemuck::Pod> ColumnData<T>
+{
+    /// `len` values at byte `offset` of `map`, if they're in bounds and aligned
+    pub(crate) fn mapped(map: &Arc<Mmap>, offset: usize, len: usize) -> Option<Self>
+    {
+        let bytes = map.get(offset..offset + len * size_of::<T>())?;
+        bytemuck::try_cast_slice::<u8, T>(bytes).ok()?;
+        Some(ColumnData::Mapped { map: Arc::clone(map), offset, len })
+    }
+}
+
+impl<T> Default for ColumnData<T>
+{
+    fn default() -> Self
+    {
+        ColumnData::Owned(Vec::new())
+    }
+}
+
+impl<T: bytemuck::Pod> Deref for ColumnData<T>
+{
+    type Target = [T];
+
+    fn deref(&self) -> &[T]
+    {
+        match self {
+            ColumnData::Owned(v) => v,
+            ColumnData::Mapped { map, offset, len } => bytemuck::cast_slice(&map[*offset..*offset + *len * size_of::<T>()]),
+        }
+    }
+}
+
+/// Bar columns and their day index, shared by every view of a series
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Columns
+{
+    pub(crate) timestamps: ColumnData<i64>,
+    pub(crate) open: ColumnData<f64>,
+    pub(crate) high: ColumnData<f64>,
+    pub(crate) low: ColumnData<f64>,
+    pub(crate) close: ColumnData<f64>,
+    pub(crate) volume: ColumnData<f64>,
+    /// Quoted bid/ask spread per bar, empty when the data has none
+    pub(crate) spread: ColumnData<f64>,
+    /// Date and first row of each run of bars on the same day
+    pub(crate) days: Vec<NaiveDate>,
+    pub(crate) day_starts: Vec<usize>,
+}
+
+/// Immutable columnar OHLCV bar data. Bars are stamped at their open, as seconds since 1970-01-01
+/// 00:00 on the wall clock of the series' zone. A series is a view of rows of columns shared through
+/// an `Arc`, so clones and slices by date or time of day copy nothing.
+#[derive(Debug, Clone, Default)]
+pub struct BarSeries
+{
+    columns: Arc<Columns>,
+    start: usize,
+    end: usize,
+}
+
+impl BarSeries
+{
+    /// Bars from their columns, which must have equal lengths
+    pub fn new(timestamps: Vec<i64>, open: Vec<f64>, high: Vec<f64>, low: Vec<f64>, close: Vec<f64>,
+               volume: Vec<f64>) -> Self
+    {
+        let n = timestamps.len();
+        assert!([open.len(), high.len(), low.len(), close.len(), volume.len()].iter().all(|&x| x == n));
+        let mut days: Vec<NaiveDate> = Vec::new();
+        let mut day_starts: Vec<usize> = Vec::new();
+        for (i, &t) in timestamps.iter().enumerate() {
+            let date = from_timestamp(t).date();
+            if days.last() != Some(&date) {
+                days.push(date);
+                day_starts.push(i);
+            }
+        }
+        use ColumnData::Owned;
+        Self::from_columns(Columns { timestamps: Owned(timestamps), open: Owned(open), high: Owned(high), low: Owned(low),
+                                     close: Owned(close), volume: Owned(volume), spread: ColumnData::default(), days,
+                                     day_starts })
+    }
+
+    /// Bars over columns whose day index is already built, such as ones mapped from a cache
+    pub(crate) fn from_columns(columns: Columns) -> Self
+    {
+        let n = columns.timestamps.len();
+        Self { columns: Arc::new(columns), start: 0, end: n }
+    }
+
+    /// Flat bars at each close with no volume, for close-only data
+    pub fn from_closes(datetimes: Vec<NaiveDateTime>, close: Vec<f64>) -> Self
+    {
+        assert_eq!(datetimes.len(), close.len());
+        Self::new(datetimes.iter().map(to_timestamp).collect(), close.clone(), close.clone(), close.clone(),
+                  close.clone(), vec![0.; close.len()])
+    }
+
+    /// Adds a spread column to a whole series
+    pub fn with_spread(mut self, spread: Vec<f64>) -> Self
+    {
+        assert_eq!(spread.len(), self.len());
+        assert_eq!((self.start, self.end), (0, self.columns.timestamps.len()));
+        Arc::make_mut(&mut self.columns).spread = ColumnData::Owned(spread);
+        self
+    }
+
+    /// Restamps bars recorded in zone `from` with wall clock times in zone `to`. Bars that don't
+    /// exist in `from` or would repeat a time in `to` when its clocks go back are dropped, with a
+    /// warning, so times stay increasing.
+    pub fn convert_timezone(self, from: Tz, to: Tz) -> Self
+    {
+        if from == to { return self }
+        let mut keep: Vec<usize> = Vec::with_capacity(self.len());
+        let mut timestamps: Vec<i64> = Vec::with_capacity(self.len());
+        let (mut nonexistent, mut repeated) = (0, 0);
+        for i in 0..self.len() {
+            match convert(self.datetime(i), from, to).map(|x| to_timestamp(&x)) {
+                Some(x) if timestamps.last().is_none_or(|last| x > *last) => {
+                    keep.push(i);
+                    timestamps.push(x);
+                },
+                Some(_) => repeated += 1,
+                None => nonexistent += 1,
+            }
+        }
+        if nonexistent + repeated > 0 {
+            warn!("Dropped {} of {} bars converting from {} to {}: {} at times that don't exist in {}, {} repeating \
+                   a time in {} when its clocks go back", nonexistent + repeated, self.len(), from, to, nonexistent,
+                  from, repeated, to);
+        }
+        self.select(keep.into_iter(), Some(timestamps))
+    }
+
+    /// Bars on the days `keep` accepts, copied into new columns
+    pub fn filter_days(&self, keep: impl Fn(NaiveDate) -> bool) -> Self
+    {
+        let rows = (0..self.days().len())
+            .filter(|&d| keep(self.days()[d]))
+            .flat_map(|d| self.day_range(d));
+        self.select(rows, None)
+    }
+
+    /// Bars at the rows `keep` accepts, copied into new columns
+    pub fn filter_rows(&self, keep: impl Fn(usize) -> bool) -> Self
+    {
+        self.select((0..self.len()).filter(|&i| keep(i)), None)
+    }
+
+    /// Copy of the rows `rows`, optionally restamped with `timestamps`
+    fn select(&self, rows: impl Iterator<Item = usize> + Clone, timestamps: Option<Vec<i64>>) -> Self
+    {
+        let select = |v: &[f64]| -> Vec<f64> { rows.clone().map(|i| v[i]).collect() };
+        let bars = Self::new(timestamps.unwrap_or_else(|| rows.clone().map(|i| self.timestamps()[i]).collect()),
+                             select(self.open()), select(self.high()), select(self.low()), select(self.close()),
+                             select(self.volume()));
+        if self.has_spread() { bars.with_spread(select(self.spreads())) } else { bars }
+    }
+
+    /// View of rows `rows` of this series
+    pub fn slice(&self, rows: Range<usize>) -> Self
+    {
+        assert!(rows.start <= rows.end && rows.end <= self.len());
+        Self { columns: Arc::clone(&self.columns), start: self.start + rows.start, end: self.start + rows.end }
+    }
+
+    /// View of the bars on `dates`
+    pub fn slice_dates(&self, dates: &RangeInclusive<NaiveDate>) -> Self
+    {
+        let days = self.days();
+        let lo = days.partition_point(|x| x < dates.start());
+        let hi = days.partition_point(|x| x <= dates.end());
+        if lo >= hi { return self.slice(0..0) }
+        self.slice(self.day_range(lo).start..self.day_range(hi - 1).end)
+    }
+
+    /// View of each day's bars stamped from `start` up to `end`, skipping days with none
+    pub fn slice_times(&self, start: NaiveTime, end: NaiveTime) -> impl Iterator<Item = BarSeries> + '_
+    {
+        (0..self.days().len()).filter_map(move |d| {
+            let day = self.day_range(d);
+            let rows = &self.timestamps()[day.clone()];
+            let time = |t: &i64| from_timestamp(*t).time();
+            let lo = day.start + rows.partition_point(|t| time(t) < start);
+            let hi = day.start + rows.partition_point(|t| time(t) < end);
+            (lo < hi).then(|| self.slice(lo..hi))
+        })
+    }
+
+    /// Dates with bars in this series, in order
+    pub fn days(&self) -> &[NaiveDate]
+    {
+        let (first, last) = self.day_bounds();
+        &self.columns.days[first..last]
+    }
+
+    /// Rows of the `d`th day of `days`
+    pub fn day_range(&self, d: usize) -> Range<usize>
+    {
+        let (first, last) = self.day_bounds();
+        assert!(first + d < last, "day {} out of range", d);
+        let starts = &self.columns.day_starts;
+        let lo = starts[first + d].max(self.start);
+        let hi = starts.get(first + d + 1).map_or(self.end, |&x| x.min(self.end));
+        lo - self.start..hi - self.start
+    }
+
+    /// Positions in the shared day index of the view's first day and one past its last
+    fn day_bounds(&self) -> (usize, usize)
+    {
+        if self.is_empty() { return (0, 0) }
+        let starts = &self.columns.day_starts;
+        (starts.partition_point(|&x| x <= self.start) - 1, starts.partition_point(|&x| x < self.end))
+    }
+
+    pub fn timestamps(&self) -> &[i64]
+    {
+        &self.columns.timestamps[self.start..self.end]
+    }
+
+    pub fn open(&self) -> &[f64]
+    {
+        &self.columns.open[self.start..self.end]
+    }
+
+    pub fn high(&self) -> &[f64]
+    {
+        &self.columns.high[self.start..self.end]
+    }
+
+    pub fn low(&self) -> &[f64]
+    {
+        &self.columns.low[self.start..self.end]
+    }
+
+    pub fn close(&self) -> &[f64]
+    {
+        &self.columns.close[self.start..self.end]
+    }
+
+    pub fn volume(&self) -> &[f64]
+    {
+        &self.columns.volume[self.start..self.end]
+    }
+
+    /// Spread column, empty when the data has none
+    pub fn spreads(&self) -> &[f64]
+    {
+        if self.has_spread() { &self.columns.spread[self.start..self.end] } else { &[] }
+    }
+
+    pub fn has_spread(&self) -> bool
+    {
+        !self.columns.spread.is_empty()
+    }
+
+    pub fn datetime(&self, i: usize) -> NaiveDateTime
+    {
+        from_timestamp(self.timestamps()[i])
+    }
+
+    pub fn date(&self, i: usize) -> NaiveDate
+    {
+        self.datetime(i).date()
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.start == self.end
+    }
+
+    /// Spread at bar `i`, zero when the data has none
+    pub fn spread(&self, i: usize) -> f64
+    {
+        self.spreads().get(i).cloned().unwrap_or(0.)
+    }
+}
@@ -0,0 +1,129 @@
+use std::hash::{Hash, Hasher};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use rustc_hash::FxHasher;
+use serde_derive::Deserialize;
+use crate::metrics::{metric_by_name, MetricSet, Observations, TRADING_DAYS};
+
+/// Bootstrap confidence intervals reported for each cell's metrics
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConfidenceConfig
+{
+    /// Metrics given lower and upper bound columns
+    #[serde(default = "default_metrics")]
+    pub metrics: Vec<String>,
+    /// Two-sided coverage of the intervals
+    #[serde(default = "default_level")]
+    pub level: f64,
+    #[serde(default = "default_samples")]
+    pub samples: usize,
+    /// Mean block length, in trades, of the stationary bootstrap; 1 resamples trades independently
+    #[serde(default = "default_block_length")]
+    pub block_length: f64,
+    /// Resample returns net of costs instead of gross, when costs are modelled
+    #[serde(default)]
+    pub net: bool,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+fn default_metrics() -> Vec<String> { ["sharpe", "mean", "hit_rate"].iter().map(|x| x.to_string()).collect() }
+fn default_level() -> f64 { 0.95 }
+fn default_samples() -> usize { 1000 }
+fn default_block_length() -> f64 { 1. }
+
+impl ConfidenceConfig
+{
+    pub fn validate(&self) -> Vec<String>
+    {
+        let mut errors: Vec<String> = Vec::new();
+        if !(self.level > 0. && self.level < 1.) || self.samples == 0 || self.block_length < 1. {
+            errors.push("confidence needs 0 < level < 1, samples > 0 and block_length >= 1".to_owned());
+        }
+        for m in self.metrics.iter() {
+            if metric_by_name(m, TRADING_DAYS).is_none() {
+                errors.push(format!("Unknown confidence metric {}", m));
+            }
+        }
+        errors
+    }
+}
+
+/// Confidence interval settings with their metrics resolved
+pub struct Confidence
+{
+    pub config: ConfidenceConfig,
+    pub metrics: MetricSet,
+}
+
+impl Confidence
+{
+    /// Lower and upper bound column names for each metric
+    pub fn field_names(&self) -> Vec<String>
+    {
+        self.metrics.metric_names(false).iter()
+            .flat_map(|name| [format!("{} lower", name), format!("{} upper", name)])
+            .collect()
+    }
+
+    /// Percentile interval bounds of each metric over resamples of `obs`, lower then upper per metric.
+    /// Resamples are seeded from the config seed and `cell`, so a cell's bounds don't depend on how
+    /// the sweep was split across threads.
+    pub fn intervals<K: Hash>(&self, obs: &Observations, cell: K) -> Vec<f64>
+    {
+        let mut hasher = FxHasher::default();
+        (self.config.seed, cell).hash(&mut hasher);
+        let samples = stationary_bootstrap(obs.len(), self.config.samples, self.config.block_length, hasher.finish());
+
+        let mut values: Vec<Vec<f64>> = vec![Vec::with_capacity(samples.len()); self.metrics.len()];
+        let mut resampled = Observations::default();
+        for ix in samples.iter()
+        {
+            resampled.returns.clear();
+            resampled.drawups.clear();
+            resampled.drawdowns.clear();
+            for &i in ix.iter() {
+                let i = i as usize;
+                resampled.push(obs.returns[i], obs.drawups[i], obs.drawdowns[i]);
+            }
+            for (v, x) in values.iter_mut().zip(self.metrics.compute(&resampled)) {
+                v.push(x);
+            }
+        }
+        let alpha = (1. - self.config.level) / 2.;
+        values.iter_mut()
+            .flat_map(|v| {
+                v.retain(|x| x.is_finite());
+                v.sort_by(|a, b| a.total_cmp(b));
+                [quantile(v, alpha), quantile(v, 1. - alpha)]
+            })
+            .collect()
+    }
+}
+
+/// Linearly interpolated `q` quantile of sorted `v`, NaN when empty
+fn quantile(v: &[f64], q: f64) -> f64
+{
+    if v.is_empty() { return f64::NAN }
+    let pos = q * (v.len() - 1) as f64;
+    let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+    v[lo] + (v[hi] - v[lo]) * (pos - lo as f64)
+}
+
+/// Politis-Romano stationary bootstrap resamples of `0..t`, with geometric blocks of mean length
+/// `block_length`
+pub fn stationary_bootstrap(t: usize, samples: usize, block_length: f64, seed: u64) -> Vec<Vec<u32>>
+{
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..samples)
+        .map(|_| {
+            let mut i = rng.gen_range(0..t);
+            (0..t).map(|_| {
+                    let x = i as u32;
+                    i = if rng.gen::<f64>() < 1. / block_length { rng.gen_range(0..t) } else { (i + 1) % t };
+                    x
+                })
+                .collect()
+        })
+        .collect()
+}
@@ -0,0 +1,182 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use chrono::{Datelike, NaiveDate};
+use log::{info, warn};
+use memmap2::Mmap;
+use rustc_hash::FxHasher;
+use simple_error::SimpleError;
+use crate::bars::{BarSeries, ColumnData, Columns};
+use crate::loader::{load_bars, load_bars_skipping_invalid, ColumnMap, InvalidRow};
+
+/// File signature and format version
+const MAGIC: &[u8; 8] = b"BTBARS02";
+/// Magic, then source length, mtime seconds and nanoseconds, fingerprint, rows, spread flag and days
+const HEADER_BYTES: usize = 8 * 8;
+/// Bytes of each end of the source hashed into its fingerprint
+const FINGERPRINT_BYTES: u64 = 1 << 16;
+
+/// What a cache must have been built from to be used: the source's length, modification time and
+/// a hash of its first and last 64KiB, so a rewrite is noticed without reading the whole file
+#[derive(Debug, PartialEq, Eq)]
+struct SourceKey
+{
+    len: u64,
+    mtime_secs: u64,
+    mtime_nanos: u64,
+    fingerprint: u64,
+}
+
+impl SourceKey
+{
+    fn of(file_name: &str) -> Result<Self, Box<dyn Error>>
+    {
+        let mut file = File::open(file_name)
+            .map_err(|e| format!("Could not open bar data {}: {}", file_name, e))?;
+        let metadata = file.metadata()?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+        let len = metadata.len();
+        let mut hasher = FxHasher::default();
+        len.hash(&mut hasher);
+        let mut buf = Vec::new();
+        (&mut file).take(FINGERPRINT_BYTES).read_to_end(&mut buf)?;
+        if len > FINGERPRINT_BYTES {
+            file.seek(SeekFrom::Start(len.saturating_sub(FINGERPRINT_BYTES).max(FINGERPRINT_BYTES)))?;
+            file.read_to_end(&mut buf)?;
+        }
+        buf.hash(&mut hasher);
+        Ok(Self { len, mtime_secs: mtime.as_secs(), mtime_nanos: mtime.subsec_nanos() as u64, fingerprint: hasher.finish() })
+    }
+}
+
+/// Cache file for bars read from `file_name` with `columns`, named by the source's stem and a hash
+/// of its canonical path and column map
+fn cache_path(cache_dir: &str, file_name: &str, columns: &ColumnMap) -> PathBuf
+{
+    let source = fs::canonicalize(file_name).unwrap_or_else(|_| PathBuf::from(file_name));
+    let mut hasher = FxHasher::default();
+    source.hash(&mut hasher);
+    format!("{:?}", columns).hash(&mut hasher);
+    let stem = source.file_stem().and_then(|x| x.to_str()).unwrap_or("bars");
+    Path::new(cache_dir).join(format!("{}-{:016x}.bars", stem, hasher.finish()))
+}
+
+/// Bars from the cache at `path` if it was built from a source matching `key`. Caches are plain
+/// native-endian columns after a fixed header, followed by the day index. The bar columns stay
+/// mapped and are borrowed by the series, so only the day index is read.
+fn read_cache(path: &Path, key: &SourceKey) -> Result<Option<BarSeries>, Box<dyn Error>>
+{
+    let file = match File::open(path) {
+        Ok(x) => x,
+        Err(_) => return Ok(None),
+    };
+    // Safety: caches are only ever replaced by renaming a complete file over them, so this mapping
+    // keeps seeing the file as it was opened
+    let map = Arc::new(unsafe { Mmap::map(&file)? });
+    let corrupt = || -> Box<dyn Error> { Box::new(SimpleError::new("truncated or corrupt")) };
+    if map.len() < HEADER_BYTES || &map[..8] != MAGIC {
+        return Err(corrupt())
+    }
+    let header: &[u64] = bytemuck::try_cast_slice(&map[8..HEADER_BYTES]).map_err(|_| corrupt())?;
+    let cached = SourceKey { len: header[0], mtime_secs: header[1], mtime_nanos: header[2], fingerprint: header[3] };
+    if cached != *key {
+        return Ok(None)
+    }
+    let (n, has_spread, n_days) = (header[4] as usize, header[5] != 0, header[6] as usize);
+    let n_columns = if has_spread { 7 } else { 6 };
+    let days_offset = HEADER_BYTES + n_columns * n * 8;
+    if map.len() != days_offset + 2 * n_days * 8 {
+        return Err(corrupt())
+    }
+    let floats = |k: usize| ColumnData::<f64>::mapped(&map, HEADER_BYTES + k * n * 8, n).ok_or_else(corrupt);
+    let words = |offset: usize| -> Result<&[i64], Box<dyn Error>> {
+        bytemuck::try_cast_slice(&map[offset..offset + n_days * 8]).map_err(|_| corrupt())
+    };
+    let days = words(days_offset)?.iter()
+        .map(|&x| i32::try_from(x).ok().and_then(NaiveDate::from_num_days_from_ce_opt))
+        .collect::<Option<Vec<NaiveDate>>>()
+        .ok_or_else(corrupt)?;
+    let day_starts: Vec<usize> = words(days_offset + n_days * 8)?.iter().map(|&x| x as usize).collect();
+    let index_valid = (n == 0 || day_starts.first() == Some(&0))
+        && day_starts.windows(2).all(|x| x[0] < x[1]) && day_starts.last().is_none_or(|&x| x < n);
+    if !index_valid {
+        return Err(corrupt())
+    }
+    let columns = Columns {
+        timestamps: ColumnData::mapped(&map, HEADER_BYTES, n).ok_or_else(corrupt)?,
+        open: floats(1)?,
+        high: floats(2)?,
+        low: floats(3)?,
+        close: floats(4)?,
+        volume: floats(5)?,
+        spread: if has_spread { floats(6)? } else { ColumnData::default() },
+        days,
+        day_starts,
+    };
+    Ok(Some(BarSeries::from_columns(columns)))
+}
+
+/// Writes `bars` to a temporary file beside `path` and renames it into place, so readers never
+/// map a partly written cache
+fn write_cache(path: &Path, key: &SourceKey, bars: &BarSeries) -> Result<(), Box<dyn Error>>
+{
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    writer.write_all(MAGIC)?;
+    let n_days = bars.days().len();
+    for x in [key.len, key.mtime_secs, key.mtime_nanos, key.fingerprint, bars.len() as u64, bars.has_spread() as u64,
+              n_days as u64] {
+        writer.write_all(&x.to_ne_bytes())?;
+    }
+    writer.write_all(bytemuck::cast_slice(bars.timestamps()))?;
+    for column in [bars.open(), bars.high(), bars.low(), bars.close(), bars.volume(), bars.spreads()] {
+        writer.write_all(bytemuck::cast_slice(column))?;
+    }
+    for x in bars.days() {
+        writer.write_all(&(x.num_days_from_ce() as i64).to_ne_bytes())?;
+    }
+    for d in 0..n_days {
+        writer.write_all(&(bars.day_range(d).start as i64).to_ne_bytes())?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Bars from `file_name`, mapped from a binary cache in `cache_dir` when one was built from the
+/// file as it is now, and otherwise parsed and cached for the next run. Rows that can't be parsed
+/// are skipped with `skip_invalid` and an error otherwise; only sources without any are cached, so
+/// a cache hit has none to report. A cache that can't be read or written is only warned about.
+pub fn load_bars_cached(file_name: &str, columns: &ColumnMap, cache_dir: &str, skip_invalid: bool)
+    -> Result<(BarSeries, Vec<InvalidRow>), Box<dyn Error>>
+{
+    let key = SourceKey::of(file_name)?;
+    let path = cache_path(cache_dir, file_name, columns);
+    match read_cache(&path, &key) {
+        Ok(Some(bars)) => {
+            info!("Loaded {} bars from cache {}", bars.len(), path.display());
+            return Ok((bars, Vec::new()))
+        },
+        Ok(None) => info!("No current bar cache for {}", file_name),
+        Err(e) => warn!("Ignoring bar cache {}: {}", path.display(), e),
+    }
+    let (bars, invalid) = if skip_invalid {
+        load_bars_skipping_invalid(file_name, columns)?
+    } else {
+        (load_bars(file_name, columns)?, Vec::new())
+    };
+    if invalid.is_empty() {
+        match write_cache(&path, &key, &bars) {
+            Ok(()) => info!("Cached bars in {}", path.display()),
+            Err(e) => warn!("Could not write bar cache {}: {}", path.display(), e),
+        }
+    }
+    Ok((bars, invalid))
+}
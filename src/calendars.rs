@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::fs::File;
+use bdays::HolidayCalendar;
+use bdays::easter::easter_naive_date;
+use chrono::{Datelike, Duration, NaiveDate, NaiveTime};
+use log::info;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{de, Deserializer};
+use serde_derive::Deserialize;
+use simple_error::SimpleError;
+use crate::config::parse_time;
+
+/// Holiday rules a trading calendar starts from
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BaseCalendar
+{
+    #[default]
+    UsSettlement,
+    WeekendsOnly,
+    /// Eurex: New Year, Good Friday, Easter Monday, Labour Day and 24-26 and 31 December
+    Eurex,
+}
+
+impl HolidayCalendar<NaiveDate> for BaseCalendar
+{
+    fn is_holiday(&self, date: NaiveDate) -> bool
+    {
+        match self {
+            BaseCalendar::UsSettlement => bdays::calendars::us::USSettlement.is_holiday(date),
+            BaseCalendar::WeekendsOnly => false,
+            BaseCalendar::Eurex => {
+                let easter = easter_naive_date(date.year()).ok();
+                easter.is_some_and(|x| date == x - Duration::days(2) || date == x + Duration::days(1))
+                    || matches!((date.month(), date.day()), (1, 1) | (5, 1) | (12, 24) | (12, 25) | (12, 26) | (12, 31))
+            },
+        }
+    }
+}
+
+/// Business days and session closes of an exchange: a base calendar's weekdays, less extra
+/// `holidays`, plus `open_days` the base would close, with early closes on `half_days`
+#[derive(Deserialize, Debug, Clone)]
+pub struct TradingCalendar
+{
+    pub name: String,
+    #[serde(default)]
+    pub base: BaseCalendar,
+    #[serde(default, deserialize_with = "deserialize_dates")]
+    pub holidays: FxHashSet<NaiveDate>,
+    #[serde(default, deserialize_with = "deserialize_dates")]
+    pub open_days: FxHashSet<NaiveDate>,
+    /// Exchange time the session closes at on each half day
+    #[serde(default, deserialize_with = "deserialize_half_days")]
+    pub half_days: FxHashMap<NaiveDate, NaiveTime>,
+}
+
+impl Default for TradingCalendar
+{
+    fn default() -> Self
+    {
+        Self::builtin("us_settlement", BaseCalendar::UsSettlement)
+    }
+}
+
+impl HolidayCalendar<NaiveDate> for TradingCalendar
+{
+    fn is_holiday(&self, date: NaiveDate) -> bool
+    {
+        !self.open_days.contains(&date) && (self.holidays.contains(&date) || self.base.is_holiday(date))
+    }
+
+    fn is_bday(&self, date: NaiveDate) -> bool
+    {
+        self.open_days.contains(&date) || !(bdays::is_weekend(date) || self.is_holiday(date))
+    }
+}
+
+impl TradingCalendar
+{
+    fn builtin(name: &str, base: BaseCalendar) -> Self
+    {
+        Self { name: name.to_owned(), base, holidays: FxHashSet::default(), open_days: FxHashSet::default(),
+               half_days: FxHashMap::default() }
+    }
+
+    /// Early session close on `date`, if it's a half day
+    pub fn early_close(&self, date: NaiveDate) -> Option<NaiveTime>
+    {
+        self.half_days.get(&date).cloned()
+    }
+}
+
+fn parse_date(s: &str) -> Result<NaiveDate, String>
+{
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|e| format!("Invalid date '{}': {}", s, e))
+}
+
+fn deserialize_dates<'de, D>(deserializer: D) -> Result<FxHashSet<NaiveDate>, D::Error>
+    where D: Deserializer<'de>
+{
+    let v: Vec<String> = serde::Deserialize::deserialize(deserializer)?;
+    v.iter().map(|s| parse_date(s)).collect::<Result<_, _>>().map_err(de::Error::custom)
+}
+
+fn deserialize_half_days<'de, D>(deserializer: D) -> Result<FxHashMap<NaiveDate, NaiveTime>, D::Error>
+    where D: Deserializer<'de>
+{
+    let m: FxHashMap<String, String> = serde::Deserialize::deserialize(deserializer)?;
+    m.iter()
+        .map(|(date, close)| Ok((parse_date(date)?, parse_time(close).map_err(|e| e.to_string())?)))
+        .collect::<Result<_, String>>()
+        .map_err(de::Error::custom)
+}
+
+/// Trading calendars by name. Starts from the built-in base calendars, which entries loaded from a
+/// file extend or replace.
+pub struct CalendarRegistry
+{
+    calendars: FxHashMap<String, TradingCalendar>,
+}
+
+impl Default for CalendarRegistry
+{
+    fn default() -> Self
+    {
+        let builtins = vec![
+            TradingCalendar::builtin("us_settlement", BaseCalendar::UsSettlement),
+            TradingCalendar::builtin("weekends_only", BaseCalendar::WeekendsOnly),
+            TradingCalendar::builtin("eurex", BaseCalendar::Eurex),
+        ];
+        Self { calendars: builtins.into_iter().map(|x| (x.name.clone(), x)).collect() }
+    }
+}
+
+impl CalendarRegistry
+{
+    /// Built-in calendars plus a YAML list of `TradingCalendar`s from `file_name`
+    pub fn from_file(file_name: &str) -> Result<Self, Box<dyn Error>>
+    {
+        info!("Reading trading calendars from {}", file_name);
+        let file = File::open(file_name)
+            .map_err(|e| format!("Could not open trading calendars {}: {}", file_name, e))?;
+        let calendars: Vec<TradingCalendar> = serde_yaml::from_reader(file)?;
+        let mut registry = Self::default();
+        for calendar in calendars {
+            registry.insert(calendar)?;
+        }
+        Ok(registry)
+    }
+
+    pub fn insert(&mut self, calendar: TradingCalendar) -> Result<(), Box<dyn Error>>
+    {
+        if let Some(date) = calendar.holidays.intersection(&calendar.open_days).next() {
+            return Err(Box::new(SimpleError::new(format!(
+                "Calendar {} has {} as both a holiday and an open day", calendar.name, date))))
+        }
+        self.calendars.insert(calendar.name.clone(), calendar);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&TradingCalendar, Box<dyn Error>>
+    {
+        self.calendars.get(name)
+            .ok_or_else(|| SimpleError::new(format!("Unknown trading calendar {}", name)).into())
+    }
+}
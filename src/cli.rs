@@ -0,0 +1,117 @@
+use std::error::Error;
+use chrono::NaiveTime;
+use clap::{Parser, Subcommand};
+use crate::config::parse_time;
+use crate::exits::StopDistance;
+use crate::strategy::Direction;
+
+/// Command line of the backtesting binary
+#[derive(Parser)]
+#[command(name = "backtesting", about = "Intraday event strategy backtester")]
+pub struct Cli
+{
+    /// Run specification file
+    #[arg(short, long, global = true, default_value = "config/run.yaml")]
+    pub config: String,
+    /// Overrides data.path
+    #[arg(long, global = true)]
+    pub data: Option<String>,
+    /// Overrides events.path
+    #[arg(long, global = true)]
+    pub events_file: Option<String>,
+    /// Overrides output.dir
+    #[arg(short, long, global = true)]
+    pub output: Option<String>,
+    /// Parse the bar data without reading or writing data.cache_dir
+    #[arg(long, global = true)]
+    pub no_cache: bool,
+    /// Metrics to report, overriding metrics. May be repeated
+    #[arg(short, long, global = true)]
+    pub metric: Vec<String>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command
+{
+    /// Sweep the interval/start time grid for each event
+    Sweep
+    {
+        /// Events to run, overriding events.names. May be repeated
+        #[arg(short, long)]
+        event: Vec<String>,
+        /// Worker threads, overriding threads. 1 runs single-threaded for profiling
+        #[arg(short, long)]
+        threads: Option<usize>,
+    },
+    /// Select cells on rolling or anchored training events and report them on the following test events
+    WalkForward
+    {
+        /// Events to run, overriding events.names. May be repeated
+        #[arg(short, long)]
+        event: Vec<String>,
+        /// Worker threads, overriding threads
+        #[arg(short, long)]
+        threads: Option<usize>,
+    },
+    /// Regress each cell's trade returns on the surprise of the event's releases
+    Surprise
+    {
+        /// Events to run, overriding events.names. May be repeated
+        #[arg(short, long)]
+        event: Vec<String>,
+        /// Worker threads, overriding threads
+        #[arg(short, long)]
+        threads: Option<usize>,
+    },
+    /// Backtest a single interval/start time pair for one event, writing its trade ledger
+    Run
+    {
+        #[arg(short, long)]
+        event: String,
+        /// Holding interval in minutes
+        #[arg(short, long)]
+        interval: u64,
+        /// Entry time, "HH:MM" or "HH:MM:SS"
+        #[arg(short, long, value_parser = parse_time_arg, required_unless_present = "offset")]
+        start_time: Option<NaiveTime>,
+        /// Entry in minutes from each release instead of a clock time, negative before it
+        #[arg(long, allow_hyphen_values = true, conflicts_with = "start_time")]
+        offset: Option<i64>,
+        /// long, short, drift:N or fade:N
+        #[arg(short, long, default_value = "long", value_parser = parse_direction_arg)]
+        direction: Direction,
+        /// Stop-loss distance from entry, ticks like 8t or percent like 0.1%
+        #[arg(long, value_parser = parse_stop_arg)]
+        stop_loss: Option<StopDistance>,
+        /// Profit target distance from entry
+        #[arg(long, value_parser = parse_stop_arg)]
+        take_profit: Option<StopDistance>,
+        /// Trailing stop distance from the best price since entry
+        #[arg(long, value_parser = parse_stop_arg)]
+        trailing_stop: Option<StopDistance>,
+        /// Minutes after entry to exit at the latest
+        #[arg(long)]
+        time_stop: Option<u64>,
+    },
+    /// List event names and release counts from the calendar
+    Events,
+    /// Summarise the loaded bar data
+    Inspect,
+}
+
+fn parse_time_arg(s: &str) -> Result<NaiveTime, String>
+{
+    parse_time(s).map_err(|e| e.to_string())
+}
+
+fn parse_direction_arg(s: &str) -> Result<Direction, String>
+{
+    s.parse().map_err(|e: Box<dyn Error>| e.to_string())
+}
+
+fn parse_stop_arg(s: &str) -> Result<StopDistance, String>
+{
+    s.parse().map_err(|e: Box<dyn Error>| e.to_string())
+}
@@ -0,0 +1,230 @@
+use std::error::Error;
+use std::fs::File;
+use std::sync::Arc;
+use arrow::array::{Array, ArrayRef, AsArray, Date32Array, Float64Array, Int64Array, StringArray, TimestampSecondArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Date32Type, Field, Float64Type, Int64Type, Schema, TimeUnit};
+use arrow::error::ArrowError;
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use log::info;
+use parquet::arrow::ArrowWriter;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use simple_error::SimpleError;
+use crate::bars::{from_timestamp, to_timestamp, BarSeries};
+use crate::events::{calendar_from_releases, parse_value, Event, EventCalendar, Impact};
+use crate::loader::{Column, ColumnMap, TimestampFormat};
+use crate::strategy::{FieldType, FieldValue, FieldsToValues};
+
+type Batches = Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>>>;
+
+fn is_parquet(file_name: &str) -> bool
+{
+    file_name.to_lowercase().ends_with(".parquet")
+}
+
+/// Record batches of a Parquet file, or else an Arrow IPC file, as they're read
+fn read_batches(file_name: &str) -> Result<Batches, Box<dyn Error>>
+{
+    let file = File::open(file_name).map_err(|e| format!("Could not open {}: {}", file_name, e))?;
+    Ok(if is_parquet(file_name) {
+        Box::new(ParquetRecordBatchReaderBuilder::try_new(file)?.build()?)
+    } else {
+        Box::new(FileReader::try_new(file, None)?)
+    })
+}
+
+fn find<'a>(batch: &'a RecordBatch, column: &Column) -> Option<&'a ArrayRef>
+{
+    match column {
+        Column::Index(i) => batch.columns().get(*i),
+        Column::Name(name) => batch.column_by_name(name),
+    }
+}
+
+fn missing(column: &Column) -> Box<dyn Error>
+{
+    Box::new(SimpleError::new(format!("No column {:?}", column)))
+}
+
+/// Values of a numeric or string column, nulls and unparseable strings as `None`
+fn float_values(array: &ArrayRef) -> Result<Vec<Option<f64>>, Box<dyn Error>>
+{
+    Ok(match array.data_type() {
+        DataType::Utf8 | DataType::LargeUtf8 => {
+            let strings = cast(array, &DataType::Utf8)?;
+            strings.as_string::<i32>().iter().map(parse_value).collect()
+        },
+        _ => cast(array, &DataType::Float64)?.as_primitive::<Float64Type>().iter().collect(),
+    })
+}
+
+fn string_values(array: &ArrayRef) -> Result<Vec<Option<String>>, Box<dyn Error>>
+{
+    Ok(cast(array, &DataType::Utf8)?.as_string::<i32>().iter().map(|x| x.map(str::to_owned)).collect())
+}
+
+/// Seconds since 1970-01-01 of a timestamp, integer or string column. Timestamps with a zone are
+/// read as UTC and integers as epoch seconds, or milliseconds with the `epoch_ms` format.
+fn timestamp_values(array: &ArrayRef, format: &TimestampFormat) -> Result<Vec<Option<i64>>, Box<dyn Error>>
+{
+    Ok(match array.data_type() {
+        DataType::Timestamp(_, _) | DataType::Date32 | DataType::Date64 => {
+            let seconds = cast(array, &DataType::Timestamp(TimeUnit::Second, None))?;
+            cast(&seconds, &DataType::Int64)?.as_primitive::<Int64Type>().iter().collect()
+        },
+        DataType::Utf8 | DataType::LargeUtf8 => cast(array, &DataType::Utf8)?.as_string::<i32>().iter()
+            .map(|x| format.parse(x?.as_bytes()))
+            .collect(),
+        _ => {
+            let millis = matches!(format, TimestampFormat::EpochMillis);
+            cast(array, &DataType::Int64)?.as_primitive::<Int64Type>().iter()
+                .map(|x| x.map(|x| if millis { x.div_euclid(1000) } else { x }))
+                .collect()
+        },
+    })
+}
+
+fn required<T>(values: Vec<Option<T>>, file_name: &str, name: &str) -> Result<Vec<T>, Box<dyn Error>>
+{
+    values.into_iter().collect::<Option<Vec<T>>>()
+        .ok_or_else(|| SimpleError::new(format!("{}: null or invalid {} values", file_name, name)).into())
+}
+
+/// Bars from a Parquet or Arrow IPC file, with columns found by name or position as in a CSV
+pub fn read_bars(file_name: &str, columns: &ColumnMap) -> Result<BarSeries, Box<dyn Error>>
+{
+    info!("Loading bars from {}", file_name);
+    let format = TimestampFormat::new(&columns.datetime_format);
+    let prices = [(&columns.open, "open"), (&columns.high, "high"), (&columns.low, "low"),
+                  (&columns.close, "close"), (&columns.volume, "volume")];
+    let mut timestamps: Vec<i64> = Vec::new();
+    let mut values: Vec<Vec<f64>> = vec![Vec::new(); prices.len()];
+    let mut spread: Vec<Option<f64>> = Vec::new();
+    for batch in read_batches(file_name)? {
+        let batch = batch?;
+        let array = find(&batch, &columns.datetime).ok_or_else(|| missing(&columns.datetime))?;
+        timestamps.extend(required(timestamp_values(array, &format)?, file_name, "datetime")?);
+        for ((column, name), v) in prices.iter().zip(values.iter_mut()) {
+            let array = find(&batch, column).ok_or_else(|| missing(column))?;
+            v.extend(required(float_values(array)?, file_name, name)?);
+        }
+        // As with CSVs, the default positional spread column is optional but a named one isn't
+        match &columns.spread {
+            Some(Column::Name(name)) if batch.column_by_name(name).is_none() => return Err(missing(&Column::Name(name.clone()))),
+            Some(column) => match find(&batch, column) {
+                Some(array) => spread.extend(float_values(array)?),
+                None => spread.extend(std::iter::repeat_n(None, batch.num_rows())),
+            },
+            None => {},
+        }
+    }
+    let [open, high, low, close, volume]: [Vec<f64>; 5] = values.try_into().unwrap();
+    let bars = BarSeries::new(timestamps, open, high, low, close, volume);
+    info!("Loaded {} bars from {}", bars.len(), file_name);
+    if spread.iter().any(|x| x.is_some()) {
+        Ok(bars.with_spread(spread.into_iter().map(|x| x.unwrap_or(0.)).collect()))
+    } else {
+        Ok(bars)
+    }
+}
+
+/// Event calendar from a Parquet or Arrow IPC file with `name` and `datetime` columns, and
+/// optionally `impact`, `currency`, `actual`, `forecast` and `previous` ones
+pub fn read_event_calendar(file_name: &str) -> Result<EventCalendar, Box<dyn Error>>
+{
+    info!("Reading event calendar from {}", file_name);
+    let format = TimestampFormat::new(&ColumnMap::default().datetime_format);
+    let mut releases: Vec<(String, Event)> = Vec::new();
+    for batch in read_batches(file_name)? {
+        let batch = batch?;
+        let column = |name: &str| batch.column_by_name(name);
+        let require = |name: &str| column(name).ok_or_else(|| missing(&Column::Name(name.to_owned())));
+        let names = required(string_values(require("name")?)?, file_name, "name")?;
+        let datetimes = required(timestamp_values(require("datetime")?, &format)?, file_name, "datetime")?;
+        let optional = |values: Option<Vec<Option<String>>>| values.unwrap_or_else(|| vec![None; batch.num_rows()]);
+        let impacts = optional(column("impact").map(string_values).transpose()?);
+        let currencies = optional(column("currency").map(string_values).transpose()?);
+        let value = |name: &str| -> Result<Vec<Option<f64>>, Box<dyn Error>> {
+            Ok(column(name).map(float_values).transpose()?.unwrap_or_else(|| vec![None; batch.num_rows()]))
+        };
+        let (actual, forecast, previous) = (value("actual")?, value("forecast")?, value("previous")?);
+        for (i, (name, datetime)) in names.into_iter().zip(datetimes).enumerate() {
+            releases.push((name, Event {
+                datetime: from_timestamp(datetime),
+                impact: Impact::parse(impacts[i].as_deref()),
+                currency: currencies[i].clone(),
+                actual: actual[i],
+                forecast: forecast[i],
+                previous: previous[i],
+                standardized_surprise: None,
+            }));
+        }
+    }
+    Ok(calendar_from_releases(releases))
+}
+
+/// Values of one column, or `None` when any isn't of the type `value` takes
+fn typed<'a, U>(column: impl Iterator<Item = &'a FieldValue>, value: impl Fn(&'a FieldValue) -> Option<U>) -> Option<Vec<U>>
+{
+    column.map(value).collect()
+}
+
+/// One record batch of `rows`, with the column types their kind of row declares
+fn to_batch<T: FieldsToValues>(rows: &[T], cols: &[&str]) -> Result<RecordBatch, Box<dyn Error>>
+{
+    let types = T::field_types(cols.len());
+    let values: Vec<Vec<FieldValue>> = rows.iter().map(|x| x.fields_to_values()).collect();
+    if types.len() != cols.len() || values.iter().any(|x| x.len() != cols.len()) {
+        return Err(Box::new(SimpleError::new(format!("Rows don't have the {} fields of {:?}", cols.len(), cols))))
+    }
+    let mut fields: Vec<Field> = Vec::with_capacity(cols.len());
+    let mut arrays: Vec<ArrayRef> = Vec::with_capacity(cols.len());
+    for (j, (name, field_type)) in cols.iter().zip(types).enumerate() {
+        let column = || values.iter().map(|x| &x[j]);
+        let mismatch = || -> Box<dyn Error> {
+            Box::new(SimpleError::new(format!("Column {} has values that aren't {:?}", name, field_type)))
+        };
+        let array: ArrayRef = match field_type {
+            FieldType::Int => Arc::new(Int64Array::from(
+                typed(column(), |x| match x { FieldValue::Int(v) => Some(*v), _ => None }).ok_or_else(mismatch)?)),
+            FieldType::Float => Arc::new(Float64Array::from(
+                typed(column(), |x| match x { FieldValue::Float(v) => Some(*v), _ => None }).ok_or_else(mismatch)?)),
+            FieldType::Date => Arc::new(Date32Array::from(
+                typed(column(), |x| match x { FieldValue::Date(v) => Some(Date32Type::from_naive_date(*v)), _ => None })
+                    .ok_or_else(mismatch)?)),
+            FieldType::DateTime => Arc::new(TimestampSecondArray::from(
+                typed(column(), |x| match x { FieldValue::DateTime(v) => Some(to_timestamp(v)), _ => None })
+                    .ok_or_else(mismatch)?)),
+            FieldType::Text => Arc::new(StringArray::from(
+                typed(column(), |x| match x { FieldValue::Text(v) => Some(v.as_str()), _ => None }).ok_or_else(mismatch)?)),
+        };
+        fields.push(Field::new(*name, array.data_type().clone(), false));
+        arrays.push(array);
+    }
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?)
+}
+
+/// Writes `rows` to a snappy compressed Parquet file at `loc`
+pub fn write_parquet<T: FieldsToValues>(rows: &[T], cols: &[&str], loc: &str) -> Result<(), Box<dyn Error>>
+{
+    let batch = to_batch(rows, cols)?;
+    let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+    let mut writer = ArrowWriter::try_new(File::create(loc)?, batch.schema(), Some(properties))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+/// Writes `rows` to an Arrow IPC file at `loc`
+pub fn write_ipc<T: FieldsToValues>(rows: &[T], cols: &[&str], loc: &str) -> Result<(), Box<dyn Error>>
+{
+    let batch = to_batch(rows, cols)?;
+    let mut writer = FileWriter::try_new(File::create(loc)?, &batch.schema())?;
+    writer.write(&batch)?;
+    writer.finish()?;
+    Ok(())
+}
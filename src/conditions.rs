@@ -0,0 +1,190 @@
+use crate::calendars::TradingCalendar;
+use chrono::{Datelike, Duration, NaiveDate};
+use bdays::HolidayCalendar;
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::bars::BarSeries;
+
+/// Bar-level filter selecting when a strategy may trade. Windows need the condition to hold on both
+/// their entry and exit bar.
+pub trait ContextCondition: Send + Sync
+{
+    /// Whether each bar of `bars` satisfies the condition
+    fn evaluate(&self, bars: &BarSeries) -> Vec<bool>;
+}
+
+/// Every bar
+pub struct Always;
+impl ContextCondition for Always
+{
+    fn evaluate(&self, bars: &BarSeries) -> Vec<bool>
+    {
+        vec![true; bars.len()]
+    }
+}
+
+/// Bars on any of a set of dates
+pub struct OnDates
+{
+    dates: FxHashSet<NaiveDate>,
+}
+
+impl OnDates
+{
+    /// Bars on the day of an event
+    pub fn day_of(event_dates: &[NaiveDate]) -> Self
+    {
+        Self { dates: event_dates.iter().cloned().collect() }
+    }
+
+    /// Bars on the days each event date minus `early_offset_days..=late_offset_days`, counted in
+    /// `calendar`'s business days when `is_bus_days` is set, as well as the event days themselves
+    pub fn day_offset(event_dates: &[NaiveDate], early_offset_days: i64, late_offset_days: i64, is_bus_days: bool,
+                      calendar: &TradingCalendar) -> Self
+    {
+        let mut dates: FxHashSet<NaiveDate> = event_dates.iter().cloned().collect();
+        for &dt in event_dates {
+            for i in early_offset_days..=late_offset_days {
+                if is_bus_days {
+                    dates.insert(calendar.advance_bdays(dt, -i as i32));
+                }
+                else {
+                    dates.insert(dt + Duration::days(-i));
+                }
+            }
+        }
+        Self { dates }
+    }
+
+    /// Bars from `before` to `after` days either side of each event date, counted in `calendar`'s
+    /// business days when `is_bus_days` is set
+    pub fn around(event_dates: &[NaiveDate], before: u32, after: u32, is_bus_days: bool, calendar: &TradingCalendar) -> Self
+    {
+        let mut dates: FxHashSet<NaiveDate> = FxHashSet::default();
+        for &dt in event_dates {
+            for i in -(before as i64)..=after as i64 {
+                if is_bus_days {
+                    dates.insert(calendar.advance_bdays(dt, i as i32));
+                }
+                else {
+                    dates.insert(dt + Duration::days(i));
+                }
+            }
+        }
+        Self { dates }
+    }
+
+    /// Bars on the day of an event's first release in each calendar month
+    pub fn first_of_month(event_dates: &[NaiveDate]) -> Self
+    {
+        let mut firsts: FxHashMap<(i32, u32), NaiveDate> = FxHashMap::default();
+        for &dt in event_dates {
+            let first = firsts.entry((dt.year(), dt.month())).or_insert(dt);
+            *first = dt.min(*first);
+        }
+        Self { dates: firsts.into_values().collect() }
+    }
+
+    /// Bars on days when at least `min_events` of the events, each given by its release dates, are
+    /// released
+    pub fn co_occurring(events: &[Vec<NaiveDate>], min_events: usize) -> Self
+    {
+        let mut counts: FxHashMap<NaiveDate, usize> = FxHashMap::default();
+        for event_dates in events {
+            let distinct: FxHashSet<&NaiveDate> = event_dates.iter().collect();
+            for &dt in distinct {
+                *counts.entry(dt).or_insert(0) += 1;
+            }
+        }
+        Self { dates: counts.into_iter().filter(|(_, n)| *n >= min_events).map(|(dt, _)| dt).collect() }
+    }
+}
+
+impl ContextCondition for OnDates
+{
+    fn evaluate(&self, bars: &BarSeries) -> Vec<bool>
+    {
+        (0..bars.len()).map(|i| self.dates.contains(&bars.date(i))).collect()
+    }
+}
+
+/// Bars in the same ISO week as an event
+pub struct WeekOf
+{
+    weeks: FxHashSet<(i32, u32)>,
+}
+
+impl WeekOf
+{
+    pub fn new(event_dates: &[NaiveDate]) -> Self
+    {
+        Self { weeks: event_dates.iter().map(|x| (x.iso_week().year(), x.iso_week().week())).collect() }
+    }
+}
+
+impl ContextCondition for WeekOf
+{
+    fn evaluate(&self, bars: &BarSeries) -> Vec<bool>
+    {
+        (0..bars.len()).map(|i| bars.date(i).iso_week()).map(|x| self.weeks.contains(&(x.year(), x.week()))).collect()
+    }
+}
+
+/// Bars whose volume is within `min..=max`
+pub struct Volume
+{
+    pub min: f64,
+    pub max: f64,
+}
+
+impl ContextCondition for Volume
+{
+    fn evaluate(&self, bars: &BarSeries) -> Vec<bool>
+    {
+        bars.volume().iter().map(|x| (self.min..=self.max).contains(x)).collect()
+    }
+}
+
+/// All of the conditions hold, trivially true when there are none
+pub struct And(pub Vec<Box<dyn ContextCondition>>);
+impl ContextCondition for And
+{
+    fn evaluate(&self, bars: &BarSeries) -> Vec<bool>
+    {
+        self.0.iter().fold(vec![true; bars.len()], |acc, c| {
+            acc.iter().zip(c.evaluate(bars)).map(|(x, y)| *x && y).collect()
+        })
+    }
+}
+
+/// Any of the conditions holds
+pub struct Or(pub Vec<Box<dyn ContextCondition>>);
+impl ContextCondition for Or
+{
+    fn evaluate(&self, bars: &BarSeries) -> Vec<bool>
+    {
+        self.0.iter().fold(vec![false; bars.len()], |acc, c| {
+            acc.iter().zip(c.evaluate(bars)).map(|(x, y)| *x || y).collect()
+        })
+    }
+}
+
+/// An odd number of the conditions hold, so exactly one of a pair
+pub struct Xor(pub Vec<Box<dyn ContextCondition>>);
+impl ContextCondition for Xor
+{
+    fn evaluate(&self, bars: &BarSeries) -> Vec<bool>
+    {
+        self.0.iter().fold(vec![false; bars.len()], |acc, c| {
+            acc.iter().zip(c.evaluate(bars)).map(|(x, y)| *x ^ y).collect()
+        })
+    }
+}
+
+pub struct Not(pub Box<dyn ContextCondition>);
+impl ContextCondition for Not
+{
+    fn evaluate(&self, bars: &BarSeries) -> Vec<bool>
+    {
+        self.0.evaluate(bars).iter().map(|x| !x).collect()
+    }
+}
@@ -0,0 +1,558 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use log::info;
+use chrono::{NaiveDate, NaiveTime, Timelike};
+use serde::{de, Deserializer};
+use serde_derive::Deserialize;
+use simple_error::SimpleError;
+use crate::utils::{time_range, OutputFormat};
+use crate::strategy::{Direction, Fill, StrategySpec};
+use crate::conditions::{And, ContextCondition, Not, OnDates, Or, Volume, WeekOf, Xor};
+use crate::events::{Event, EventCalendar, ReleaseFilter};
+use crate::metrics::{metric_by_name, MetricSet, DEFAULT_METRICS, TRADING_DAYS};
+use crate::costs::CostModel;
+use crate::contracts::{ContractRegistry, ContractSpec, Units};
+use crate::exits::ExitGrid;
+use crate::walkforward::WalkForwardConfig;
+use crate::significance::SignificanceConfig;
+use crate::bootstrap::{Confidence, ConfidenceConfig};
+use crate::window::CellTime;
+use crate::timezones::{parse_timezone, TimeZones};
+use crate::calendars::{CalendarRegistry, TradingCalendar};
+use crate::loader::{is_columnar, ColumnMap};
+use crate::quality::QualityConfig;
+
+/// Run specification loaded from a YAML file, replacing the paths and sweep grids that used to be
+/// hardcoded in `main.rs`. See `config/run.yaml` for an annotated example. Command line flags may
+/// override fields after loading, so `validate` is left to the caller.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RunConfig
+{
+    pub data: DataConfig,
+    pub events: EventsConfig,
+    pub output: OutputConfig,
+    pub sweep: SweepConfig,
+    #[serde(default = "default_conditions")]
+    pub conditions: Vec<ConditionConfig>,
+    /// Sides reported for every cell: long, short, {drift: N} or {fade: N} with N bars of lookback
+    #[serde(default = "default_directions")]
+    pub directions: Vec<Direction>,
+    /// Price entries and scheduled exits fill at: close of the bar stamped at the start/end time, or
+    /// its open
+    #[serde(default)]
+    pub fill: Fill,
+    /// Stop-loss, take-profit, trailing and time stop alternatives, swept as one more grid dimension
+    #[serde(default)]
+    pub exits: ExitGrid,
+    /// Contract symbol looked up in the contract registry, for tick size, point value and session hours
+    #[serde(default = "default_instrument")]
+    pub instrument: String,
+    /// YAML list of extra contract specs, extending or replacing the built-in ones
+    #[serde(default)]
+    pub contracts: Option<String>,
+    /// Trading calendar for business day offsets and half days, overriding the contract's
+    #[serde(default)]
+    pub holiday_calendar: Option<String>,
+    /// YAML list of extra trading calendars, extending or replacing the built-in ones
+    #[serde(default)]
+    pub calendars: Option<String>,
+    /// Units of trade returns, excursions and costs: points, ticks or currency per contract
+    #[serde(default)]
+    pub units: Units,
+    /// Transaction costs; when present every metric is also reported net of costs
+    #[serde(default)]
+    pub costs: Option<CostModel>,
+    /// Metric names from `metrics::metric_by_name`, written as CSV columns in this order
+    #[serde(default = "default_metrics")]
+    pub metrics: Vec<String>,
+    /// Bootstrap confidence interval columns for each cell's metrics
+    #[serde(default)]
+    pub confidence: Option<ConfidenceConfig>,
+    /// When set, sweeps also test their cells for data snooping and overfitting
+    #[serde(default)]
+    pub significance: Option<SignificanceConfig>,
+    /// Train/test fold layout and cell selection for the walk-forward command
+    #[serde(default)]
+    pub walk_forward: WalkForwardConfig,
+    #[serde(default = "default_threads")]
+    pub threads: usize,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DataConfig
+{
+    pub path: String,
+    /// IANA timezone bar timestamps are recorded in, when not the contract's
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Columns of the bar fields and timestamp format
+    #[serde(default)]
+    pub columns: ColumnMap,
+    /// Directory of binary caches of the parsed bars, reused while the source is unchanged. Null
+    /// parses the source every run.
+    #[serde(default = "default_cache_dir")]
+    pub cache_dir: Option<String>,
+    /// Checks run on the bars as loaded and what to do about the issues they find
+    #[serde(default)]
+    pub quality: QualityConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EventsConfig
+{
+    pub path: String,
+    /// IANA timezone release times are recorded in, when not the contract's
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Restrict the run to these event names; all events in the calendar are run when absent
+    #[serde(default)]
+    pub names: Option<Vec<String>>,
+    /// Business days kept either side of each event date before the sweep runs
+    #[serde(default = "default_bdays")]
+    pub back_bdays: u32,
+    #[serde(default = "default_bdays")]
+    pub fwd_bdays: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OutputConfig
+{
+    pub dir: String,
+    /// csv, parquet or arrow
+    #[serde(default)]
+    pub format: OutputFormat,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SweepConfig
+{
+    /// Step in minutes shared by the interval and start time grids
+    #[serde(default = "default_resolution")]
+    pub resolution: u64,
+    pub interval: IntervalRange,
+    /// Clock times windows open at each day
+    #[serde(default)]
+    pub start_time: Option<TimeRange>,
+    /// Instead of `start_time`, minutes from each release of the event that windows open at,
+    /// negative before it. Holding intervals are then measured from the entry as usual.
+    #[serde(default)]
+    pub offset: Option<OffsetRange>,
+}
+
+/// Holding interval grid in minutes, inclusive of both ends
+#[derive(Deserialize, Debug, Clone)]
+pub struct IntervalRange
+{
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Entry offset grid in minutes from a release, inclusive of both ends
+#[derive(Deserialize, Debug, Clone)]
+pub struct OffsetRange
+{
+    pub start: i64,
+    pub end: i64,
+}
+
+/// Start time grid, inclusive of both ends. Times are written as "HH:MM" or "HH:MM:SS"
+#[derive(Deserialize, Debug, Clone)]
+pub struct TimeRange
+{
+    #[serde(deserialize_with = "deserialize_time")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub end: NaiveTime,
+}
+
+/// Context conditions selecting the bars a strategy trades on, composable with `and`, `or`, `xor`
+/// and `not`; a run's list of conditions is ANDed. An `event` of `None` refers to the event
+/// currently being swept.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ConditionConfig
+{
+    DayOf
+    {
+        #[serde(default)]
+        event: Option<String>,
+        #[serde(default)]
+        filter: ReleaseFilter,
+    },
+    DayOffset
+    {
+        #[serde(default)]
+        event: Option<String>,
+        #[serde(default)]
+        filter: ReleaseFilter,
+        early_offset_days: i64,
+        late_offset_days: i64,
+        #[serde(default = "default_true")]
+        is_bus_days: bool,
+    },
+    /// Bars whose volume is within `min..=max`
+    Volume
+    {
+        #[serde(default)]
+        min: f64,
+        #[serde(default = "default_max_volume")]
+        max: f64,
+    },
+    /// Days from `before` to `after` days either side of each release, such as the few business
+    /// days after FOMC
+    Within
+    {
+        #[serde(default)]
+        event: Option<String>,
+        #[serde(default)]
+        filter: ReleaseFilter,
+        #[serde(default)]
+        before: u32,
+        #[serde(default)]
+        after: u32,
+        #[serde(default = "default_true")]
+        is_bus_days: bool,
+    },
+    /// Days of the event's first release in each calendar month
+    FirstOfMonth
+    {
+        #[serde(default)]
+        event: Option<String>,
+        #[serde(default)]
+        filter: ReleaseFilter,
+    },
+    /// Days on which at least `min_events` of `events` are also released, by default any other
+    /// event in the calendar. Wrap in `not` to exclude clashing releases.
+    CoOccurs
+    {
+        #[serde(default)]
+        events: Option<Vec<String>>,
+        #[serde(default)]
+        filter: ReleaseFilter,
+        #[serde(default = "default_min_events")]
+        min_events: usize,
+    },
+    /// Bars in the same ISO week as the event
+    WeekOf
+    {
+        #[serde(default)]
+        event: Option<String>,
+        #[serde(default)]
+        filter: ReleaseFilter,
+    },
+    And { conditions: Vec<ConditionConfig> },
+    Or { conditions: Vec<ConditionConfig> },
+    /// An odd number of `conditions` hold
+    Xor { conditions: Vec<ConditionConfig> },
+    Not { condition: Box<ConditionConfig> },
+}
+
+fn default_conditions() -> Vec<ConditionConfig>
+{
+    vec![ConditionConfig::DayOf { event: None, filter: ReleaseFilter::default() }]
+}
+fn default_instrument() -> String { "ZN".to_owned() }
+fn default_directions() -> Vec<Direction> { vec![Direction::Long] }
+fn default_metrics() -> Vec<String> { DEFAULT_METRICS.iter().map(|x| x.to_string()).collect() }
+fn default_threads() -> usize { 12 }
+fn default_cache_dir() -> Option<String> { Some("cache".to_owned()) }
+fn default_bdays() -> u32 { 1 }
+fn default_resolution() -> u64 { 1 }
+fn default_true() -> bool { true }
+fn default_max_volume() -> f64 { f64::INFINITY }
+fn default_min_events() -> usize { 1 }
+
+pub fn parse_time(s: &str) -> Result<NaiveTime, Box<dyn Error>>
+{
+    NaiveTime::parse_from_str(s, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+        .map_err(|e| format!("Invalid time '{}': {}", s, e).into())
+}
+
+pub(crate) fn deserialize_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+    where D: Deserializer<'de>
+{
+    let s: String = serde::Deserialize::deserialize(deserializer)?;
+    parse_time(&s).map_err(de::Error::custom)
+}
+
+impl RunConfig
+{
+    pub fn from_file(file_name: &str) -> Result<Self, Box<dyn Error>>
+    {
+        info!("Reading run config from {}", file_name);
+        let file = File::open(file_name)
+            .map_err(|e| format!("Could not open config {}: {}", file_name, e))?;
+        let config: RunConfig = serde_yaml::from_reader(file)?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>>
+    {
+        let mut errors: Vec<String> = Vec::new();
+        if !Path::new(&self.data.path).is_file() {
+            errors.push(format!("data.path {} is not a file", self.data.path));
+        }
+        if !Path::new(&self.events.path).is_file() {
+            errors.push(format!("events.path {} is not a file", self.events.path));
+        }
+        if self.sweep.resolution == 0 {
+            errors.push("sweep.resolution must be positive".to_owned());
+        }
+        let interval = &self.sweep.interval;
+        let interval_valid = interval.start > 0 && interval.start <= interval.end;
+        if !interval_valid {
+            errors.push(format!("sweep.interval {}..={} is empty or starts at zero", interval.start, interval.end));
+        }
+        let start_times_valid = match (&self.sweep.start_time, &self.sweep.offset) {
+            (Some(t), None) if t.start > t.end => {
+                errors.push(format!("sweep.start_time {}..={} is empty", t.start, t.end));
+                false
+            },
+            (None, Some(o)) if o.start > o.end => {
+                errors.push(format!("sweep.offset {}..={} is empty", o.start, o.end));
+                false
+            },
+            (Some(_), Some(_)) | (None, None) => {
+                errors.push("sweep needs exactly one of start_time and offset".to_owned());
+                false
+            },
+            _ => true,
+        };
+        // A range can still hold no point of the grid at the sweep's resolution
+        if self.sweep.resolution > 0 {
+            if interval_valid && self.sweep.interval_rng().is_empty() {
+                errors.push(format!("sweep.interval {}..={} has no multiple of resolution {}",
+                                    interval.start, interval.end, self.sweep.resolution));
+            }
+            if start_times_valid && self.sweep.start_time_rng().is_empty() {
+                errors.push(format!("sweep start times have no step at resolution {}", self.sweep.resolution));
+            }
+        }
+        for m in self.metrics.iter() {
+            if metric_by_name(m, TRADING_DAYS).is_none() {
+                errors.push(format!("Unknown metric {}", m));
+            }
+        }
+        if self.directions.is_empty() {
+            errors.push("directions must not be empty".to_owned());
+        }
+        errors.extend(self.exits.validate());
+        errors.extend(self.walk_forward.validate());
+        errors.extend(self.data.quality.validate());
+        if let Some(confidence) = &self.confidence {
+            errors.extend(confidence.validate());
+        }
+        if let Some(significance) = &self.significance {
+            errors.extend(significance.validate());
+        }
+        let select_by = &self.walk_forward.select_by;
+        let (select_metric, select_net) = match select_by.strip_prefix("net_") {
+            Some(x) => (x, true),
+            None => (select_by.as_str(), false),
+        };
+        if !self.metrics.iter().any(|m| m == select_metric) || (select_net && self.costs.is_none()) {
+            errors.push(format!("walk_forward select_by {} is not a reported metric", select_by));
+        }
+        if let Some(costs) = &self.costs {
+            if costs.point_value.is_some_and(|x| x <= 0.) || costs.tick_size.is_some_and(|x| x < 0.)
+                || costs.commission < 0. || costs.slippage_ticks < 0. {
+                errors.push("costs must be non-negative, with a positive point_value".to_owned());
+            }
+        }
+        if let Some(file_name) = &self.contracts {
+            if !Path::new(file_name).is_file() {
+                errors.push(format!("contracts {} is not a file", file_name));
+            }
+        }
+        if !cfg!(feature = "columnar") {
+            for path in [&self.data.path, &self.events.path].into_iter().filter(|x| is_columnar(x)) {
+                errors.push(format!("Reading {} needs the columnar feature", path));
+            }
+            if self.output.format != OutputFormat::Csv {
+                errors.push(format!("output.format {} needs the columnar feature", self.output.format.extension()));
+            }
+        }
+        if let Some(file_name) = &self.calendars {
+            if !Path::new(file_name).is_file() {
+                errors.push(format!("calendars {} is not a file", file_name));
+            }
+        }
+        if let Err(e) = self.timezones() {
+            errors.push(e.to_string());
+        }
+        if let Err(e) = self.trading_calendar() {
+            errors.push(e.to_string());
+        }
+        if self.threads == 0 {
+            errors.push("threads must be at least 1".to_owned());
+        }
+        for c in self.conditions.iter() {
+            errors.extend(c.validate());
+        }
+
+        match errors.len() {
+            0 => Ok(()),
+            _ => Err(Box::new(SimpleError::new(format!("Invalid run config: {}", errors.join("; "))))),
+        }
+    }
+}
+
+impl RunConfig
+{
+    pub fn contract(&self) -> Result<ContractSpec, Box<dyn Error>>
+    {
+        let registry = match &self.contracts {
+            Some(file_name) => ContractRegistry::from_file(file_name)?,
+            None => ContractRegistry::default(),
+        };
+        Ok(registry.get(&self.instrument)?.clone())
+    }
+
+    /// The run's `holiday_calendar`, or else the contract's
+    pub fn trading_calendar(&self) -> Result<TradingCalendar, Box<dyn Error>>
+    {
+        let registry = match &self.calendars {
+            Some(file_name) => CalendarRegistry::from_file(file_name)?,
+            None => CalendarRegistry::default(),
+        };
+        let name = match &self.holiday_calendar {
+            Some(x) => x.clone(),
+            None => self.contract()?.holiday_calendar,
+        };
+        Ok(registry.get(&name)?.clone())
+    }
+
+    /// The configured conditions ANDed together
+    pub fn context_condition(&self, event_name: &str, calendar: &EventCalendar, trading_calendar: &TradingCalendar)
+                             -> Result<Box<dyn ContextCondition>, Box<dyn Error>>
+    {
+        ConditionConfig::And { conditions: self.conditions.clone() }.build(event_name, calendar, trading_calendar)
+    }
+
+    /// Zones of the bar data and calendar, which default to the contract's
+    pub fn timezones(&self) -> Result<TimeZones, Box<dyn Error>>
+    {
+        let exchange = parse_timezone(&self.contract()?.timezone)?;
+        let zone = |name: &Option<String>| name.as_deref().map_or(Ok(exchange), parse_timezone);
+        Ok(TimeZones { data: zone(&self.data.timezone)?, events: zone(&self.events.timezone)?, exchange })
+    }
+
+    pub fn strategy_spec(&self) -> Result<StrategySpec, Box<dyn Error>>
+    {
+        let contract = self.contract()?;
+        Ok(StrategySpec {
+            directions: self.directions.clone(),
+            fill: self.fill,
+            exit_rules: self.exits.rules(),
+            costs: self.costs.as_ref().map(|c| c.with_contract(&contract)),
+            metrics: MetricSet::from_names(&self.metrics, contract.trading_days)?,
+            confidence: match &self.confidence {
+                Some(c) => Some(Confidence {
+                    metrics: MetricSet::from_names(&c.metrics, contract.trading_days)?,
+                    config: c.clone(),
+                }),
+                None => None,
+            },
+            units: self.units,
+            calendar: self.trading_calendar()?,
+            contract,
+        })
+    }
+}
+
+impl SweepConfig
+{
+    pub fn interval_rng(&self) -> Vec<u64>
+    {
+        (self.interval.start..=self.interval.end).filter(|x| x.is_multiple_of(self.resolution)).collect()
+    }
+
+    /// Entry times swept: clock times from `start_time`, or release offsets from `offset`
+    pub fn start_time_rng(&self) -> Vec<CellTime>
+    {
+        match (&self.start_time, &self.offset) {
+            (_, Some(o)) => (o.start..=o.end).step_by(self.resolution as usize).map(CellTime::Release).collect(),
+            (Some(t), None) => {
+                let (st, et) = (t.start, t.end);
+                time_range((st.hour(), st.minute(), st.second()), (et.hour(), et.minute(), et.second()), self.resolution)
+                    .into_iter().map(CellTime::Clock).collect()
+            },
+            (None, None) => Vec::new(),
+        }
+    }
+}
+
+/// Dates of the releases `filter` keeps
+fn release_dates(events: &[Event], filter: &ReleaseFilter) -> Vec<NaiveDate>
+{
+    events.iter().filter(|x| filter.matches(x)).map(|x| x.datetime.date()).collect()
+}
+
+impl ConditionConfig
+{
+    pub fn validate(&self) -> Vec<String>
+    {
+        match self {
+            ConditionConfig::DayOffset { early_offset_days, late_offset_days, .. }
+                if early_offset_days > late_offset_days =>
+                vec![format!("day_offset condition has early_offset_days {} > late_offset_days {}",
+                             early_offset_days, late_offset_days)],
+            ConditionConfig::Volume { min, max } if min > max =>
+                vec![format!("volume condition has min {} > max {}", min, max)],
+            ConditionConfig::CoOccurs { min_events: 0, .. } =>
+                vec!["co_occurs condition needs min_events of at least 1".to_owned()],
+            ConditionConfig::Or { conditions } | ConditionConfig::Xor { conditions } if conditions.is_empty() =>
+                vec!["or and xor conditions need at least one condition".to_owned()],
+            ConditionConfig::And { conditions } | ConditionConfig::Or { conditions }
+                | ConditionConfig::Xor { conditions } => conditions.iter().flat_map(|c| c.validate()).collect(),
+            ConditionConfig::Not { condition } => condition.validate(),
+            ConditionConfig::DayOf { filter, .. } | ConditionConfig::DayOffset { filter, .. }
+                | ConditionConfig::Within { filter, .. } | ConditionConfig::FirstOfMonth { filter, .. }
+                | ConditionConfig::CoOccurs { filter, .. } | ConditionConfig::WeekOf { filter, .. } => filter.validate(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Builds the condition tree, resolving event names against the loaded calendar and counting
+    /// business days in `trading_calendar`
+    pub fn build(&self, event_name: &str, calendar: &EventCalendar, trading_calendar: &TradingCalendar)
+                 -> Result<Box<dyn ContextCondition>, Box<dyn Error>>
+    {
+        let lookup = |event: &Option<String>, filter: &ReleaseFilter| {
+            let name = event.as_deref().unwrap_or(event_name);
+            calendar.get(name)
+                .map(|x| release_dates(x, filter))
+                .ok_or_else(|| SimpleError::new(format!("Condition references unknown event {}", name)))
+        };
+        let build_all = |conditions: &[ConditionConfig]| {
+            conditions.iter().map(|c| c.build(event_name, calendar, trading_calendar)).collect::<Result<Vec<_>, _>>()
+        };
+        Ok(match self {
+            ConditionConfig::DayOf { event, filter } => Box::new(OnDates::day_of(&lookup(event, filter)?)),
+            ConditionConfig::DayOffset { event, filter, early_offset_days, late_offset_days, is_bus_days } =>
+                Box::new(OnDates::day_offset(&lookup(event, filter)?, *early_offset_days, *late_offset_days,
+                                             *is_bus_days, trading_calendar)),
+            ConditionConfig::Volume { min, max } => Box::new(Volume { min: *min, max: *max }),
+            ConditionConfig::Within { event, filter, before, after, is_bus_days } =>
+                Box::new(OnDates::around(&lookup(event, filter)?, *before, *after, *is_bus_days, trading_calendar)),
+            ConditionConfig::FirstOfMonth { event, filter } => Box::new(OnDates::first_of_month(&lookup(event, filter)?)),
+            ConditionConfig::CoOccurs { events, filter, min_events } => {
+                let others: Vec<Vec<NaiveDate>> = match events {
+                    Some(names) => names.iter().map(|x| lookup(&Some(x.clone()), filter)).collect::<Result<_, _>>()?,
+                    None => calendar.iter()
+                        .filter(|(name, _)| *name != event_name)
+                        .map(|(_, x)| release_dates(x, filter))
+                        .collect(),
+                };
+                Box::new(OnDates::co_occurring(&others, *min_events))
+            },
+            ConditionConfig::WeekOf { event, filter } => Box::new(WeekOf::new(&lookup(event, filter)?)),
+            ConditionConfig::And { conditions } => Box::new(And(build_all(conditions)?)),
+            ConditionConfig::Or { conditions } => Box::new(Or(build_all(conditions)?)),
+            ConditionConfig::Xor { conditions } => Box::new(Xor(build_all(conditions)?)),
+            ConditionConfig::Not { condition } => Box::new(Not(condition.build(event_name, calendar, trading_calendar)?)),
+        })
+    }
+}
@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::fs::File;
+use log::info;
+use chrono::NaiveTime;
+use rustc_hash::FxHashMap;
+use serde_derive::Deserialize;
+use simple_error::SimpleError;
+use crate::config::deserialize_time;
+
+/// Futures contract specification. Session times are in the contract's `timezone`; a session that
+/// opens after it closes starts on the previous calendar day.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContractSpec
+{
+    pub symbol: String,
+    pub tick_size: f64,
+    /// Currency value of one tick per contract
+    pub tick_value: f64,
+    pub currency: String,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub session_open: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub session_close: NaiveTime,
+    /// IANA timezone name the session times and bar timestamps are quoted in
+    pub timezone: String,
+    pub holiday_calendar: String,
+    /// Trading days per year used to annualize metrics
+    #[serde(default = "default_trading_days")]
+    pub trading_days: f64,
+}
+
+fn default_trading_days() -> f64 { 252. }
+
+/// Unit trade returns, excursions and costs are reported in
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Units
+{
+    #[default]
+    Points,
+    Ticks,
+    Currency,
+}
+
+impl ContractSpec
+{
+    /// Currency value of a one point move per contract
+    pub fn point_value(&self) -> f64
+    {
+        self.tick_value / self.tick_size
+    }
+
+    /// Whether `time` falls in the session. One opening after it closes runs from the open through
+    /// midnight, so only times from the close to the open are outside it.
+    pub fn in_session(&self, time: &NaiveTime) -> bool
+    {
+        if self.session_open < self.session_close {
+            self.session_open <= *time && *time < self.session_close
+        } else {
+            *time >= self.session_open || *time < self.session_close
+        }
+    }
+
+    /// Multiplier converting price points to `units`
+    pub fn unit_scale(&self, units: Units) -> f64
+    {
+        match units {
+            Units::Points => 1.,
+            Units::Ticks => 1. / self.tick_size,
+            Units::Currency => self.point_value(),
+        }
+    }
+
+    fn builtin(symbol: &str, tick_size: f64, tick_value: f64, open: (u32, u32), close: (u32, u32)) -> Self
+    {
+        Self {
+            symbol: symbol.to_owned(),
+            tick_size,
+            tick_value,
+            currency: "USD".to_owned(),
+            session_open: NaiveTime::from_hms_opt(open.0, open.1, 0).unwrap(),
+            session_close: NaiveTime::from_hms_opt(close.0, close.1, 0).unwrap(),
+            timezone: "America/Chicago".to_owned(),
+            holiday_calendar: "us_settlement".to_owned(),
+            trading_days: default_trading_days(),
+        }
+    }
+}
+
+/// Contract specifications by symbol. Starts from built-in CME contracts, which entries loaded from
+/// a file extend or replace.
+pub struct ContractRegistry
+{
+    specs: FxHashMap<String, ContractSpec>,
+}
+
+impl Default for ContractRegistry
+{
+    fn default() -> Self
+    {
+        let builtins = vec![
+            // Globex contracts trade 17:00-16:00 Chicago time
+            // Treasury futures
+            ContractSpec::builtin("ZT", 1./256., 7.8125, (17, 0), (16, 0)),
+            ContractSpec::builtin("ZF", 1./256., 7.8125, (17, 0), (16, 0)),
+            ContractSpec::builtin("ZN", 1./64., 15.625, (17, 0), (16, 0)),
+            ContractSpec::builtin("ZB", 1./32., 31.25, (17, 0), (16, 0)),
+            // Equity index futures
+            ContractSpec::builtin("ES", 0.25, 12.5, (17, 0), (16, 0)),
+            ContractSpec::builtin("NQ", 0.25, 5., (17, 0), (16, 0)),
+            // Energy and metals
+            ContractSpec::builtin("CL", 0.01, 10., (17, 0), (16, 0)),
+            ContractSpec::builtin("GC", 0.1, 10., (17, 0), (16, 0)),
+            // FX
+            ContractSpec::builtin("6E", 0.00005, 6.25, (17, 0), (16, 0)),
+        ];
+        Self { specs: builtins.into_iter().map(|x| (x.symbol.clone(), x)).collect() }
+    }
+}
+
+impl ContractRegistry
+{
+    /// Built-in contracts plus a YAML list of `ContractSpec`s from `file_name`
+    pub fn from_file(file_name: &str) -> Result<Self, Box<dyn Error>>
+    {
+        info!("Reading contract specs from {}", file_name);
+        let file = File::open(file_name)
+            .map_err(|e| format!("Could not open contract specs {}: {}", file_name, e))?;
+        let specs: Vec<ContractSpec> = serde_yaml::from_reader(file)?;
+        let mut registry = Self::default();
+        for spec in specs {
+            registry.insert(spec)?;
+        }
+        Ok(registry)
+    }
+
+    pub fn insert(&mut self, spec: ContractSpec) -> Result<(), Box<dyn Error>>
+    {
+        if spec.tick_size <= 0. || spec.tick_value <= 0. || spec.trading_days <= 0. {
+            return Err(Box::new(SimpleError::new(format!(
+                "Contract {} needs a positive tick_size, tick_value and trading_days", spec.symbol))))
+        }
+        self.specs.insert(spec.symbol.clone(), spec);
+        Ok(())
+    }
+
+    pub fn get(&self, symbol: &str) -> Result<&ContractSpec, Box<dyn Error>>
+    {
+        self.specs.get(symbol)
+            .ok_or_else(|| SimpleError::new(format!("Unknown contract {}", symbol)).into())
+    }
+}
@@ -0,0 +1,47 @@
+use serde_derive::Deserialize;
+use crate::contracts::ContractSpec;
+
+/// Per-trade transaction costs, converted to price points so they net directly off trade returns.
+/// Commission and slippage are charged on both entry and exit. Point value and tick size default
+/// to the traded contract's.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct CostModel
+{
+    /// Commission per contract per side, in currency
+    #[serde(default)]
+    pub commission: f64,
+    /// Currency value of one point of price, used to convert commission to points
+    #[serde(default)]
+    pub point_value: Option<f64>,
+    /// Ticks of slippage per side
+    #[serde(default)]
+    pub slippage_ticks: f64,
+    #[serde(default)]
+    pub tick_size: Option<f64>,
+    /// Also pay half the bid/ask spread on entry and exit, from the bar data's spread column
+    #[serde(default)]
+    pub use_spread: bool,
+}
+
+
+impl CostModel
+{
+    /// Copy with point value and tick size taken from `contract` where not set explicitly
+    pub fn with_contract(&self, contract: &ContractSpec) -> Self
+    {
+        Self {
+            point_value: self.point_value.or(Some(contract.point_value())),
+            tick_size: self.tick_size.or(Some(contract.tick_size)),
+            ..self.clone()
+        }
+    }
+
+    /// Round trip cost in price points given the quoted spreads at entry and exit
+    pub fn round_trip(&self, entry_spread: f64, exit_spread: f64) -> f64
+    {
+        let per_side = self.commission / self.point_value.unwrap_or(1.)
+            + self.slippage_ticks * self.tick_size.unwrap_or(0.);
+        let spread = if self.use_spread { (entry_spread + exit_spread) / 2. } else { 0. };
+        2. * per_side + spread
+    }
+}
@@ -1,45 +1,233 @@
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, ParseError};
+use chrono_tz::Tz;
+use csv::{ReaderBuilder, StringRecord};
+use log::{info, warn};
 use std::collections::hash_map::Entry;
+use std::error::Error;
 use rustc_hash::FxHashMap;
-use crate::utils::read_csv;
+use simple_error::SimpleError;
+use crate::timezones::convert;
+use crate::vector_utils::vec_std;
 use serde_derive::Deserialize;
 
+/// Calendar CSV row: name and release time, then optional trailing impact, currency and actual,
+/// forecast and previous value columns
 #[derive(Deserialize, Debug, Clone)]
 pub struct EventRow {
     // _id: String,
     pub name: String,
     _dt: String,
-    // _impact: String,
-    // pub currency: String,
+    #[serde(default)]
+    _impact: Option<String>,
+    #[serde(default)]
+    pub currency: Option<String>,
+    #[serde(default)]
+    _actual: Option<String>,
+    #[serde(default)]
+    _forecast: Option<String>,
+    #[serde(default)]
+    _previous: Option<String>,
 }
 impl EventRow {
-    pub fn datetime(&self) -> NaiveDateTime {
+    pub fn datetime(&self) -> Result<NaiveDateTime, ParseError> {
         NaiveDateTime::parse_from_str(&self._dt,
                                       "%Y-%m-%d %H:%M:%S")
-            .unwrap()
     }
 
-    // pub fn impact(&self) -> i32 {
-    //     match self._impact.as_str() {
-    //         "LOW" => 1,
-    //         "MED" => 2,
-    //         "HIGH" => 3,
-    //         _ => 0,
-    //     }
-    // }
+    pub fn impact(&self) -> Impact {
+        Impact::parse(self._impact.as_deref())
+    }
 }
 
-pub fn get_event_calendar(file_name: &str) -> FxHashMap<String, Vec<NaiveDateTime>> {
-    let raw: Vec<EventRow> = read_csv(file_name).unwrap().into_iter()
-        // .filter(|x: &EventRow| impacts.contains(&x.impact()))
-        .collect();
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Impact
+{
+    #[default]
+    Unknown,
+    Low,
+    Medium,
+    High,
+}
 
-    let mut hm:FxHashMap<String, Vec<NaiveDateTime>> = FxHashMap::default();
-    for row in raw {
-        match hm.entry(row.name.clone()) {
-            Entry::Vacant(e) => { e.insert(vec![row.datetime()]); },
-            Entry::Occupied(mut e) => { e.get_mut().push(row.datetime()); }
+impl Impact
+{
+    /// Calendar impact label such as "LOW", "MED" or "HIGH", in any case
+    pub fn parse(s: Option<&str>) -> Self
+    {
+        match s.map(|x| x.trim().to_uppercase()).as_deref() {
+            Some("LOW") => Impact::Low,
+            Some("MED") | Some("MEDIUM") => Impact::Medium,
+            Some("HIGH") => Impact::High,
+            _ => Impact::Unknown,
         }
     }
+}
+
+/// One release of an event
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event
+{
+    pub datetime: NaiveDateTime,
+    pub impact: Impact,
+    pub currency: Option<String>,
+    pub actual: Option<f64>,
+    pub forecast: Option<f64>,
+    pub previous: Option<f64>,
+    /// Surprise divided by the standard deviation of the event's surprises
+    pub standardized_surprise: Option<f64>,
+}
+
+impl Event
+{
+    /// A release with no impact, currency or values
+    pub fn at(datetime: NaiveDateTime) -> Self
+    {
+        Self { datetime, impact: Impact::Unknown, currency: None, actual: None, forecast: None, previous: None,
+               standardized_surprise: None }
+    }
+
+    /// Actual less forecast value
+    pub fn surprise(&self) -> Option<f64>
+    {
+        Some(self.actual? - self.forecast?)
+    }
+}
+
+/// Releases of each event by name, in file order
+pub type EventCalendar = FxHashMap<String, Vec<Event>>;
+
+/// Parses a calendar value such as "1.5", "-0.2%" or "250K", scaling K, M and B suffixes
+pub fn parse_value(s: Option<&str>) -> Option<f64> {
+    let s = s?.trim().trim_end_matches('%');
+    let (number, scale) = match s.chars().last()? {
+        'K' | 'k' => (&s[..s.len()-1], 1e3),
+        'M' | 'm' => (&s[..s.len()-1], 1e6),
+        'B' | 'b' => (&s[..s.len()-1], 1e9),
+        _ => (s, 1.),
+    };
+    number.trim().parse::<f64>().ok().map(|x| x * scale)
+}
+
+/// Event calendar from the CSV at `file_name`, failing with the line of the first row that can't
+/// be read
+pub fn get_event_calendar(file_name: &str) -> Result<EventCalendar, Box<dyn Error>> {
+    info!("Reading event calendar from {}", file_name);
+    let mut rdr = ReaderBuilder::new().from_path(file_name)
+        .map_err(|e| format!("Could not open event calendar {}: {}", file_name, e))?;
+    let mut releases: Vec<(String, Event)> = Vec::new();
+    let mut record = StringRecord::new();
+    while rdr.read_record(&mut record)? {
+        let line = record.position().map_or(0, |x| x.line());
+        let invalid = |msg: String| -> Box<dyn Error> {
+            Box::new(SimpleError::new(format!("{} line {}: {}", file_name, line, msg)))
+        };
+        let row: EventRow = record.deserialize(None).map_err(|e| invalid(e.to_string()))?;
+        let datetime = row.datetime().map_err(|e| invalid(format!("invalid datetime '{}': {}", row._dt, e)))?;
+        let event = Event {
+            datetime,
+            impact: row.impact(),
+            currency: row.currency.clone(),
+            actual: parse_value(row._actual.as_deref()),
+            forecast: parse_value(row._forecast.as_deref()),
+            previous: parse_value(row._previous.as_deref()),
+            standardized_surprise: None,
+        };
+        releases.push((row.name, event));
+    }
+    Ok(calendar_from_releases(releases))
+}
+
+/// Groups named releases into a calendar, in order, and standardizes each event's surprises
+pub fn calendar_from_releases(releases: impl IntoIterator<Item = (String, Event)>) -> EventCalendar {
+    let mut hm: EventCalendar = FxHashMap::default();
+    for (name, event) in releases {
+        match hm.entry(name) {
+            Entry::Vacant(e) => { e.insert(vec![event]); },
+            Entry::Occupied(mut e) => { e.get_mut().push(event); }
+        }
+    }
+    for events in hm.values_mut() {
+        standardize_surprises(events);
+    }
     hm
 }
+
+/// Restamps every release recorded in zone `from` with its wall clock time in zone `to`, dropping
+/// releases at times that don't exist in `from`
+pub fn convert_calendar(calendar: EventCalendar, from: Tz, to: Tz) -> EventCalendar {
+    if from == to { return calendar }
+    calendar.into_iter()
+        .map(|(name, events)| {
+            let converted = events.into_iter()
+                .filter_map(|e| match convert(e.datetime, from, to) {
+                    Some(datetime) => Some(Event { datetime, ..e }),
+                    None => { warn!("Dropping {} release at {}, which doesn't exist in {}", name, e.datetime, from); None },
+                })
+                .collect();
+            (name, converted)
+        })
+        .collect()
+}
+
+/// Sets each release's standardized surprise from the spread of the event's known surprises
+pub fn standardize_surprises(events: &mut [Event]) {
+    let surprises: Vec<f64> = events.iter().filter_map(|x| x.surprise()).collect();
+    let std = vec_std(&surprises).unwrap_or(f64::NAN);
+    for e in events.iter_mut() {
+        e.standardized_surprise = e.surprise().map(|x| x / std).filter(|x| x.is_finite());
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SurpriseSign
+{
+    Positive,
+    Negative,
+}
+
+/// Which releases of an event a condition counts. Releases without the data a criterion needs never
+/// match it.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ReleaseFilter
+{
+    #[serde(default)]
+    pub min_impact: Option<Impact>,
+    #[serde(default)]
+    pub currencies: Option<Vec<String>>,
+    /// Sign of actual less forecast
+    #[serde(default)]
+    pub surprise: Option<SurpriseSign>,
+    /// Bounds on the absolute standardized surprise
+    #[serde(default)]
+    pub min_abs_surprise: Option<f64>,
+    #[serde(default)]
+    pub max_abs_surprise: Option<f64>,
+}
+
+impl ReleaseFilter
+{
+    pub fn matches(&self, event: &Event) -> bool
+    {
+        let z = event.standardized_surprise.map(f64::abs);
+        self.min_impact.is_none_or(|x| event.impact >= x)
+            && self.currencies.as_ref().is_none_or(|x| event.currency.as_ref().is_some_and(|c| x.contains(c)))
+            && self.surprise.is_none_or(|x| match (x, event.surprise()) {
+                (SurpriseSign::Positive, Some(s)) => s > 0.,
+                (SurpriseSign::Negative, Some(s)) => s < 0.,
+                (_, None) => false,
+            })
+            && self.min_abs_surprise.is_none_or(|x| z.is_some_and(|z| z >= x))
+            && self.max_abs_surprise.is_none_or(|x| z.is_some_and(|z| z <= x))
+    }
+
+    pub fn validate(&self) -> Vec<String>
+    {
+        match (self.min_abs_surprise, self.max_abs_surprise) {
+            (Some(lo), Some(hi)) if lo > hi =>
+                vec![format!("release filter has min_abs_surprise {} > max_abs_surprise {}", lo, hi)],
+            _ => Vec::new(),
+        }
+    }
+}
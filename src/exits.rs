@@ -0,0 +1,237 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use itertools::iproduct;
+use serde_derive::Deserialize;
+use crate::strategy::Fill;
+use crate::window::WindowEngine;
+
+/// Distance of a stop or target from a reference price
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopDistance
+{
+    Ticks(f64),
+    /// Percent of the reference price
+    Percent(f64),
+}
+
+impl StopDistance
+{
+    pub fn is_positive(&self) -> bool
+    {
+        match self {
+            StopDistance::Ticks(x) | StopDistance::Percent(x) => *x > 0.,
+        }
+    }
+
+    pub fn to_points(&self, price: f64, tick_size: f64) -> f64
+    {
+        match self {
+            StopDistance::Ticks(n) => n * tick_size,
+            StopDistance::Percent(pct) => price * pct / 100.,
+        }
+    }
+}
+
+impl FromStr for StopDistance
+{
+    type Err = Box<dyn Error>;
+    /// `8t` or a bare `8` for ticks, `0.1%` for percent
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let parse = |x: &str| x.parse::<f64>()
+            .map_err(|_| format!("Invalid stop distance '{}', expected ticks like 8t or percent like 0.1%", s));
+        let d = match s.strip_suffix('%') {
+            Some(x) => StopDistance::Percent(parse(x)?),
+            None => StopDistance::Ticks(parse(s.strip_suffix('t').unwrap_or(s))?),
+        };
+        Ok(d)
+    }
+}
+
+impl fmt::Display for StopDistance
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            StopDistance::Ticks(n) => write!(f, "{}t", n),
+            StopDistance::Percent(pct) => write!(f, "{}%", pct),
+        }
+    }
+}
+
+/// Intraday exits checked bar by bar before the window's scheduled exit. Stops and targets are
+/// measured from the entry price, the trailing stop from the best price since entry, and the time
+/// stop in minutes after entry.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ExitRule
+{
+    pub stop_loss: Option<StopDistance>,
+    pub take_profit: Option<StopDistance>,
+    pub trailing_stop: Option<StopDistance>,
+    pub time_stop: Option<u64>,
+}
+
+impl ExitRule
+{
+    pub fn is_none(&self) -> bool
+    {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for ExitRule
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        if self.is_none() { return write!(f, "none") }
+        let mut parts: Vec<String> = Vec::new();
+        if let Some(x) = self.stop_loss { parts.push(format!("sl={}", x)); }
+        if let Some(x) = self.take_profit { parts.push(format!("tp={}", x)); }
+        if let Some(x) = self.trailing_stop { parts.push(format!("ts={}", x)); }
+        if let Some(x) = self.time_stop { parts.push(format!("tm={}", x)); }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// Exit rule sweep dimensions, each a list of alternatives where `null` means no such exit
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExitGrid
+{
+    #[serde(default = "no_stop")]
+    pub stop_loss: Vec<Option<StopDistance>>,
+    #[serde(default = "no_stop")]
+    pub take_profit: Vec<Option<StopDistance>>,
+    #[serde(default = "no_stop")]
+    pub trailing_stop: Vec<Option<StopDistance>>,
+    #[serde(default = "no_time_stop")]
+    pub time_stop: Vec<Option<u64>>,
+}
+
+fn no_stop() -> Vec<Option<StopDistance>> { vec![None] }
+fn no_time_stop() -> Vec<Option<u64>> { vec![None] }
+
+impl Default for ExitGrid
+{
+    fn default() -> Self
+    {
+        Self { stop_loss: no_stop(), take_profit: no_stop(), trailing_stop: no_stop(), time_stop: no_time_stop() }
+    }
+}
+
+impl ExitGrid
+{
+    pub fn validate(&self) -> Vec<String>
+    {
+        let mut errors: Vec<String> = Vec::new();
+        if self.stop_loss.is_empty() || self.take_profit.is_empty() || self.trailing_stop.is_empty()
+            || self.time_stop.is_empty() {
+            errors.push("exits lists must not be empty, use null for no exit".to_owned());
+        }
+        let distances = self.stop_loss.iter().chain(self.take_profit.iter()).chain(self.trailing_stop.iter());
+        if distances.flatten().any(|d| !d.is_positive()) || self.time_stop.contains(&Some(0)) {
+            errors.push("exit distances and time stops must be positive".to_owned());
+        }
+        errors
+    }
+
+    /// Every combination of the grid's dimensions
+    pub fn rules(&self) -> Vec<ExitRule>
+    {
+        iproduct!(self.stop_loss.iter(), self.take_profit.iter(), self.trailing_stop.iter(), self.time_stop.iter())
+            .map(|(&stop_loss, &take_profit, &trailing_stop, &time_stop)|
+                ExitRule { stop_loss, take_profit, trailing_stop, time_stop })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason
+{
+    /// Scheduled end of the window
+    End,
+    StopLoss,
+    TakeProfit,
+    TrailingStop,
+    TimeStop,
+}
+
+impl fmt::Display for ExitReason
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let s = match self {
+            ExitReason::End => "end",
+            ExitReason::StopLoss => "stop loss",
+            ExitReason::TakeProfit => "take profit",
+            ExitReason::TrailingStop => "trailing stop",
+            ExitReason::TimeStop => "time stop",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+pub struct Exit
+{
+    /// Bar the exit is stamped at
+    pub ix: usize,
+    /// Last bar the trade was exposed to, for its excursions
+    pub last_bar: usize,
+    pub price: f64,
+    pub reason: ExitReason,
+}
+
+/// Walks the bars held after entry until `rule` triggers, or exits at the `fill` price of `exit_ix`.
+/// Levels are checked against each bar's high and low and fill at the level, or at the bar's open
+/// when it gaps through. When a stop and a target are both touched in one bar the stop is assumed to
+/// fill first. The time stop exits at the first fill price at or past its limit.
+pub fn simulate_exit(engine: &WindowEngine, entry_ix: usize, exit_ix: usize, side: f64, rule: &ExitRule,
+                     fill: Fill, tick_size: f64) -> Exit
+{
+    let bars = &engine.bars;
+    let entry_price = fill.price(bars, entry_ix);
+    let (first, last) = fill.held_bars(entry_ix, exit_ix);
+    let scheduled = Exit { ix: exit_ix, last_bar: last, price: fill.price(bars, exit_ix), reason: ExitReason::End };
+    if rule.is_none() { return scheduled }
+
+    // Work in side-adjusted prices so a long and a short share the same comparisons
+    let adj = |x: f64| x * side;
+    let stop_level = rule.stop_loss.map(|d| adj(entry_price) - d.to_points(entry_price, tick_size));
+    let target_level = rule.take_profit.map(|d| adj(entry_price) + d.to_points(entry_price, tick_size));
+    let time_limit = rule.time_stop.map(|m| bars.datetime(entry_ix) + chrono::Duration::minutes(m as i64));
+    let time_up = |i: usize| time_limit.is_some_and(|t| bars.datetime(i) >= t);
+    let mut best = adj(entry_price);
+
+    for i in first..=last
+    {
+        if fill == Fill::Open && i > entry_ix && time_up(i)
+        {
+            return Exit { ix: i, last_bar: i - 1, price: bars.open()[i], reason: ExitReason::TimeStop }
+        }
+        let open = adj(bars.open()[i]);
+        let (worst_px, best_px) = if side > 0. {
+            (bars.low()[i], bars.high()[i])
+        } else {
+            (-bars.high()[i], -bars.low()[i])
+        };
+        let trail_level = rule.trailing_stop.map(|d| best - d.to_points(entry_price, tick_size));
+        let stops = [(stop_level, ExitReason::StopLoss), (trail_level, ExitReason::TrailingStop)];
+        if let Some((level, reason)) = stops.iter()
+            .filter_map(|&(level, reason)| level.filter(|&l| worst_px <= l).map(|l| (l, reason)))
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+        {
+            return Exit { ix: i, last_bar: i, price: adj(level.min(open)), reason }
+        }
+        if let Some(level) = target_level.filter(|&l| best_px >= l)
+        {
+            return Exit { ix: i, last_bar: i, price: adj(level.max(open)), reason: ExitReason::TakeProfit }
+        }
+        best = best.max(best_px);
+        if fill == Fill::Close && i < last && time_up(i)
+        {
+            return Exit { ix: i, last_bar: i, price: bars.close()[i], reason: ExitReason::TimeStop }
+        }
+    }
+    scheduled
+}
@@ -1,4 +1,3 @@
-static BUS_DAY_CAL:bdays::calendars::us::USSettlement = bdays::calendars::us::USSettlement;
 // static LOGGER: log4rs::config::file = log4rs::init_file("config/log4rs.yaml", Default::default()).unwrap();
 
 pub mod vector_utils;
@@ -6,6 +5,26 @@ pub mod utils;
 pub mod strategy;
 pub mod events;
 pub mod analysis;
+pub mod config;
+pub mod cli;
+pub mod bars;
+pub mod window;
+pub mod metrics;
+pub mod costs;
+pub mod contracts;
+pub mod exits;
+pub mod walkforward;
+pub mod significance;
+pub mod bootstrap;
+pub mod conditions;
+pub mod regression;
+pub mod timezones;
+pub mod calendars;
+pub mod loader;
+pub mod cache;
+pub mod quality;
+#[cfg(feature = "columnar")]
+pub mod columnar;
 
 #[cfg(test)]
 mod test;
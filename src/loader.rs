@@ -0,0 +1,268 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use chrono::{NaiveDate, NaiveDateTime};
+use csv::{ByteRecord, ReaderBuilder, StringRecord};
+use log::info;
+use serde_derive::Deserialize;
+use simple_error::SimpleError;
+use crate::bars::{to_timestamp, BarSeries};
+use crate::events::{get_event_calendar, EventCalendar};
+
+/// A data file column, by header name or zero-based index
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Column
+{
+    Index(usize),
+    Name(String),
+}
+
+/// Where the bar fields are in the data file and how its timestamps are written. Defaults to the
+/// datetime, open, high, low, close, volume and optional spread columns in that order.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ColumnMap
+{
+    #[serde(default = "default_datetime")]
+    pub datetime: Column,
+    #[serde(default = "default_open")]
+    pub open: Column,
+    #[serde(default = "default_high")]
+    pub high: Column,
+    #[serde(default = "default_low")]
+    pub low: Column,
+    #[serde(default = "default_close")]
+    pub close: Column,
+    #[serde(default = "default_volume")]
+    pub volume: Column,
+    /// Quoted bid/ask spread, used by the bars that have one. A value there that isn't a number
+    /// makes the row invalid.
+    #[serde(default = "default_spread")]
+    pub spread: Option<Column>,
+    /// chrono format of the datetime column, or `epoch`/`epoch_ms` for seconds/milliseconds since
+    /// 1970-01-01
+    #[serde(default = "default_datetime_format")]
+    pub datetime_format: String,
+}
+
+fn default_datetime() -> Column { Column::Index(0) }
+fn default_open() -> Column { Column::Index(1) }
+fn default_high() -> Column { Column::Index(2) }
+fn default_low() -> Column { Column::Index(3) }
+fn default_close() -> Column { Column::Index(4) }
+fn default_volume() -> Column { Column::Index(5) }
+fn default_spread() -> Option<Column> { Some(Column::Index(6)) }
+fn default_datetime_format() -> String { DEFAULT_DATETIME_FORMAT.to_owned() }
+
+const DEFAULT_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// Fraction of the file read between progress messages
+const PROGRESS_STEP: f64 = 0.1;
+
+impl Default for ColumnMap
+{
+    fn default() -> Self
+    {
+        Self { datetime: default_datetime(), open: default_open(), high: default_high(), low: default_low(),
+               close: default_close(), volume: default_volume(), spread: default_spread(),
+               datetime_format: default_datetime_format() }
+    }
+}
+
+impl Column
+{
+    fn resolve(&self, headers: &StringRecord) -> Result<usize, Box<dyn Error>>
+    {
+        match self {
+            Column::Index(i) => Ok(*i),
+            Column::Name(name) => headers.iter().position(|x| x.trim() == name)
+                .ok_or_else(|| SimpleError::new(format!("No column named {}", name)).into()),
+        }
+    }
+}
+
+/// How a datetime field is parsed, decided once per file
+pub(crate) enum TimestampFormat
+{
+    Default,
+    Epoch,
+    EpochMillis,
+    Custom(String),
+}
+
+impl TimestampFormat
+{
+    pub(crate) fn new(format: &str) -> Self
+    {
+        match format {
+            DEFAULT_DATETIME_FORMAT => TimestampFormat::Default,
+            "epoch" => TimestampFormat::Epoch,
+            "epoch_ms" => TimestampFormat::EpochMillis,
+            x => TimestampFormat::Custom(x.to_owned()),
+        }
+    }
+
+    pub(crate) fn parse(&self, field: &[u8]) -> Option<i64>
+    {
+        match self {
+            TimestampFormat::Default => parse_default_datetime(field),
+            TimestampFormat::Epoch => parse_field::<i64>(field),
+            TimestampFormat::EpochMillis => parse_field::<i64>(field).map(|x| x.div_euclid(1000)),
+            TimestampFormat::Custom(format) => NaiveDateTime::parse_from_str(std::str::from_utf8(field).ok()?.trim(), format)
+                .ok().map(|x| to_timestamp(&x)),
+        }
+    }
+}
+
+/// "YYYY-MM-DD HH:MM:SS" read straight from the bytes, skipping chrono's format interpreter
+fn parse_default_datetime(field: &[u8]) -> Option<i64>
+{
+    let s = field.trim_ascii();
+    if s.len() != 19 || s[4] != b'-' || s[7] != b'-' || s[10] != b' ' || s[13] != b':' || s[16] != b':' {
+        return None
+    }
+    let number = |range: std::ops::Range<usize>| -> Option<u32> {
+        s[range].iter().try_fold(0_u32, |acc, &b| b.is_ascii_digit().then(|| acc * 10 + (b - b'0') as u32))
+    };
+    let date = NaiveDate::from_ymd_opt(number(0..4)? as i32, number(5..7)?, number(8..10)?)?;
+    Some(to_timestamp(&date.and_hms_opt(number(11..13)?, number(14..16)?, number(17..19)?)?))
+}
+
+fn parse_field<T: std::str::FromStr>(field: &[u8]) -> Option<T>
+{
+    std::str::from_utf8(field).ok()?.trim().parse::<T>().ok()
+}
+
+/// A data row that couldn't be parsed, by its line in the file
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidRow
+{
+    pub line: u64,
+    pub reason: String,
+}
+
+/// Whether `file_name` is Parquet or Arrow IPC rather than CSV, by its extension
+pub fn is_columnar(file_name: &str) -> bool
+{
+    let extension = Path::new(file_name).extension().and_then(|x| x.to_str()).map(|x| x.to_lowercase());
+    matches!(extension.as_deref(), Some("parquet" | "arrow" | "ipc" | "feather"))
+}
+
+#[cfg(not(feature = "columnar"))]
+fn columnar_disabled(file_name: &str) -> Box<dyn Error>
+{
+    Box::new(SimpleError::new(format!("Reading {} needs the columnar feature", file_name)))
+}
+
+/// Bars from a CSV, Parquet or Arrow IPC file, failing on the first row that can't be parsed
+pub fn load_bars(file_name: &str, columns: &ColumnMap) -> Result<BarSeries, Box<dyn Error>>
+{
+    if is_columnar(file_name) {
+        #[cfg(feature = "columnar")]
+        return crate::columnar::read_bars(file_name, columns);
+        #[cfg(not(feature = "columnar"))]
+        return Err(columnar_disabled(file_name));
+    }
+    load_csv_bars(file_name, columns, None)
+}
+
+/// Bars from a CSV, Parquet or Arrow IPC file, leaving out the CSV rows that can't be parsed.
+/// Columnar files have typed columns, so still fail on nulls.
+pub fn load_bars_skipping_invalid(file_name: &str, columns: &ColumnMap)
+    -> Result<(BarSeries, Vec<InvalidRow>), Box<dyn Error>>
+{
+    if is_columnar(file_name) {
+        return Ok((load_bars(file_name, columns)?, Vec::new()))
+    }
+    let mut invalid: Vec<InvalidRow> = Vec::new();
+    let bars = load_csv_bars(file_name, columns, Some(&mut invalid))?;
+    Ok((bars, invalid))
+}
+
+/// Event calendar from a CSV, Parquet or Arrow IPC file
+pub fn load_event_calendar(file_name: &str) -> Result<EventCalendar, Box<dyn Error>>
+{
+    if is_columnar(file_name) {
+        #[cfg(feature = "columnar")]
+        return crate::columnar::read_event_calendar(file_name);
+        #[cfg(not(feature = "columnar"))]
+        return Err(columnar_disabled(file_name));
+    }
+    get_event_calendar(file_name)
+}
+
+/// Streams bars from the CSV at `file_name` straight into columns, reusing one record buffer and
+/// parsing each timestamp once. The first row is a header, and progress is logged every tenth of
+/// the file. Rows that can't be parsed are an error, unless collected in `invalid`.
+fn load_csv_bars(file_name: &str, columns: &ColumnMap, mut invalid: Option<&mut Vec<InvalidRow>>)
+    -> Result<BarSeries, Box<dyn Error>>
+{
+    info!("Loading bars from {}", file_name);
+    let file = File::open(file_name)
+        .map_err(|e| format!("Could not open bar data {}: {}", file_name, e))?;
+    let file_len = file.metadata()?.len().max(1);
+    let mut rdr = ReaderBuilder::new().flexible(true).from_reader(BufReader::new(file));
+    let headers = rdr.headers()?.clone();
+    let datetime_ix = columns.datetime.resolve(&headers)?;
+    let price_ix = [columns.open.resolve(&headers)?, columns.high.resolve(&headers)?,
+                    columns.low.resolve(&headers)?, columns.close.resolve(&headers)?,
+                    columns.volume.resolve(&headers)?];
+    // A named spread column must exist, but the default trailing one is optional
+    let spread_ix = match &columns.spread {
+        Some(Column::Index(i)) => Some(*i),
+        Some(x) => Some(x.resolve(&headers)?),
+        None => None,
+    };
+    let format = TimestampFormat::new(&columns.datetime_format);
+
+    let mut timestamps: Vec<i64> = Vec::new();
+    let (mut open, mut high, mut low, mut close, mut volume, mut spread) =
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let mut has_spread = false;
+    let mut record = ByteRecord::new();
+    let mut next_progress = PROGRESS_STEP;
+    while rdr.read_byte_record(&mut record)? {
+        let line = record.position().map_or(0, |x| x.line());
+        let parsed = record.get(datetime_ix).and_then(|x| format.parse(x)).ok_or("datetime").and_then(|t| {
+            let mut prices = [0.; 5];
+            for (x, (&ix, name)) in prices.iter_mut().zip(price_ix.iter().zip(["open", "high", "low", "close", "volume"])) {
+                *x = record.get(ix).and_then(parse_field::<f64>).ok_or(name)?;
+            }
+            // Rows without a value in the spread column have no spread, but one that's there must parse
+            let spread = match spread_ix.and_then(|ix| record.get(ix)).filter(|x| !x.is_empty()) {
+                Some(x) => Some(parse_field::<f64>(x).ok_or("spread")?),
+                None => None,
+            };
+            Ok((t, prices, spread))
+        });
+        let (timestamp, prices, s) = match (parsed, invalid.as_mut()) {
+            (Ok(x), _) => x,
+            (Err(name), Some(invalid)) => {
+                invalid.push(InvalidRow { line, reason: format!("invalid or missing {}", name) });
+                continue
+            },
+            (Err(name), None) => return Err(Box::new(SimpleError::new(
+                format!("{} line {}: invalid or missing {}", file_name, line, name)))),
+        };
+        timestamps.push(timestamp);
+        open.push(prices[0]);
+        high.push(prices[1]);
+        low.push(prices[2]);
+        close.push(prices[3]);
+        volume.push(prices[4]);
+        has_spread |= s.is_some();
+        spread.push(s.unwrap_or(0.));
+
+        let read = rdr.position().byte() as f64 / file_len as f64;
+        if read >= next_progress && read < 1. {
+            info!("Loaded {} bars, {:.0}% of {}", timestamps.len(), read * 100., file_name);
+            next_progress = (read / PROGRESS_STEP).floor() * PROGRESS_STEP + PROGRESS_STEP;
+        }
+    }
+    let mut bars = BarSeries::new(timestamps, open, high, low, close, volume);
+    if has_spread {
+        bars = bars.with_spread(spread);
+    }
+    info!("Loaded {} bars from {}", bars.len(), file_name);
+    Ok(bars)
+}
@@ -1,37 +1,116 @@
-use std::{env, fs};
-use std::thread;
+use std::fs;
 use std::sync::{Arc, Mutex};
 use std::error::Error;
 use log::{error, info, warn};
-use log4rs;
-use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::Parser;
+use rayon::prelude::*;
 use backtesting::strategy::StrategyResult;
 use backtesting::utils::*;
 use backtesting::events::*;
-use backtesting::analysis::run_analysis;
+use backtesting::analysis::{run_analysis, run_trades};
+use backtesting::cli::{Cli, Command};
+use backtesting::config::RunConfig;
+use backtesting::window::{CellTime, WindowEngine};
+use backtesting::bars::BarSeries;
+use backtesting::loader::{load_bars, load_bars_skipping_invalid, load_event_calendar};
+use backtesting::cache::load_bars_cached;
+use backtesting::quality::{validate_bars, IssueKind, Policy, QualityReport};
+use backtesting::exits::{ExitGrid, ExitRule};
+use backtesting::walkforward::{walk_forward, WalkForwardReport};
+use backtesting::regression::{surprise_regressions, SURPRISE_FIELD_NAMES};
+use backtesting::significance::{self, CELL_SIGNIFICANCE_FIELD_NAMES, SIGNIFICANCE_SUMMARY_FIELD_NAMES};
 use std::time::Instant;
-use rustc_hash::FxHashMap;
 use backtesting::strategy::*;
 
-
 fn main() -> Result<(), Box<dyn Error>>
 {
     // Set up logging
     log4rs::init_file("config/log4rs.yaml", Default::default()).unwrap();
-    let file_name = "C:\\Users\\mbroo\\IdeaProjects\\backtesting\\ZN_continuous_adjusted_1min.csv";
-    let data: Vec<Row> = read_csv(file_name).unwrap();
+    let cli = Cli::parse();
+
+    let mut config = match RunConfig::from_file(&cli.config)
+    {
+        Ok(c) => c,
+        Err(e) => { error!("{}", e); return Err(e) }
+    };
+    if let Some(x) = cli.data { config.data.path = x; }
+    if let Some(x) = cli.events_file { config.events.path = x; }
+    if let Some(x) = cli.output { config.output.dir = x; }
+    if cli.no_cache { config.data.cache_dir = None; }
+    if !cli.metric.is_empty() { config.metrics = cli.metric; }
+
+    let res = match cli.command
+    {
+        Command::Sweep { event, threads } => run_event_command(config, event, threads, main_routine),
+        Command::WalkForward { event, threads } => run_event_command(config, event, threads, walk_forward_routine),
+        Command::Surprise { event, threads } => run_event_command(config, event, threads, surprise_routine),
+        Command::Run { event, interval, start_time, offset, direction, stop_loss, take_profit, trailing_stop,
+                       time_stop } =>
+        {
+            let start_time = match (start_time, offset) {
+                (_, Some(m)) => CellTime::Release(m),
+                (Some(t), None) => CellTime::Clock(t),
+                (None, None) => unreachable!("clap requires start_time or offset"),
+            };
+            let exit_rule = ExitRule { stop_loss, take_profit, trailing_stop, time_stop };
+            config.exits = ExitGrid {
+                stop_loss: vec![stop_loss],
+                take_profit: vec![take_profit],
+                trailing_stop: vec![trailing_stop],
+                time_stop: vec![time_stop],
+            };
+            config.validate().and_then(|_| run_single(&config, &event, interval, start_time, direction, &exit_rule))
+        },
+        Command::Events => config.validate().and_then(|_| list_events(&config)),
+        Command::Inspect => config.validate().and_then(|_| inspect(&config)),
+    };
+    if let Err(e) = &res
+    {
+        error!("{}", e);
+    }
+    res
+}
 
-    let events_loc = "C:\\Users\\mbroo\\PycharmProjects\\backtesting\\calendar-event-list-new.csv";
-    let event_data: FxHashMap<String, Vec<NaiveDateTime>> = get_event_calendar(events_loc);
-    // let event_data = event_data.iter().filter(|(s,_)| s==&&"Retail Sales MoM".to_owned()); // test case
+type EventRoutine = fn(&BarSeries, &str, &[Event], &EventCalendar, &RunConfig)
+                        -> Result<(), Box<dyn Error>>;
+
+/// Applies a per-event command's event and thread overrides, validates the config, sizes the rayon
+/// pool and runs `routine` for each event
+fn run_event_command(mut config: RunConfig, event: Vec<String>, threads: Option<usize>, routine: EventRoutine)
+                     -> Result<(), Box<dyn Error>>
+{
+    if !event.is_empty() { config.events.names = Some(event); }
+    if let Some(x) = threads { config.threads = x; }
+    config.validate()?;
+    rayon::ThreadPoolBuilder::new().num_threads(config.threads).thread_name(|i| i.to_string()).build_global()?;
+    run_events(&config, routine)
+}
+
+/// Runs `routine` for each configured event in name order, logging and skipping failed events
+fn run_events(config: &RunConfig, routine: EventRoutine) -> Result<(), Box<dyn Error>>
+{
+    let data = load_exchange_data(config)?;
+
+    let event_data: EventCalendar = load_calendar(config)?;
+    let mut event_names: Vec<&String> = match &config.events.names
+    {
+        Some(names) => names.iter().collect(),
+        None => event_data.keys().collect(),
+    };
+    event_names.sort();
 
-    let output_path = "C:\\Users\\mbroo\\IdeaProjects\\backtesting\\output\\full";
     let start = Instant::now();
-    for (event_name, events) in event_data
+    for event_name in event_names
     {
+        let events = match event_data.get(event_name)
+        {
+            Some(x) => x,
+            None => { error!("Event {} not found in {}", event_name, config.events.path); continue }
+        };
         println!("Running event: {}", event_name);
         let now = Instant::now();
-        match main_routine(&data, event_name.as_str(), &events, output_path)
+        match routine(&data, event_name.as_str(), events, &event_data, config)
         {
             Ok(()) => println!("Ran {} in {}s", event_name, now.elapsed().as_secs()),
             Err(e) => { error!("{} {}", event_name, e); continue }
@@ -41,118 +120,254 @@ fn main() -> Result<(), Box<dyn Error>>
     Ok(())
 }
 
-fn main_routine(data: &Vec<Row>, event_name: &str, events: &Vec<NaiveDateTime>, output_path: &str) -> Result<(), Box<dyn Error>>
+/// The bar data, through the cache when one is configured, after the data quality checks
+fn load_data(config: &RunConfig) -> Result<(BarSeries, QualityReport), Box<dyn Error>>
+{
+    let (path, columns) = (&config.data.path, &config.data.columns);
+    let skip_invalid = config.data.quality.policy(IssueKind::Unparseable) != Policy::Fail;
+    let (data, invalid) = match &config.data.cache_dir {
+        Some(dir) => load_bars_cached(path, columns, dir, skip_invalid)?,
+        None if skip_invalid => load_bars_skipping_invalid(path, columns)?,
+        None => (load_bars(path, columns)?, Vec::new()),
+    };
+    validate_bars(data, invalid, &config.data.quality)
+}
+
+/// The bar data restamped in the exchange's zone, which event days are dated in
+fn load_exchange_data(config: &RunConfig) -> Result<BarSeries, Box<dyn Error>>
+{
+    let zones = config.timezones()?;
+    let (data, _) = load_data(config)?;
+    Ok(data.convert_timezone(zones.data, zones.exchange))
+}
+
+/// The event calendar with release times in the exchange's zone
+fn load_calendar(config: &RunConfig) -> Result<EventCalendar, Box<dyn Error>>
+{
+    let zones = config.timezones()?;
+    Ok(convert_calendar(load_event_calendar(&config.events.path)?, zones.events, zones.exchange))
+}
+
+/// Filters the bars, already in the exchange's zone, to the event's days and indexes them, with the
+/// configured context conditions
+fn event_series(data: &BarSeries, event_name: &str, events: &[Event],
+                event_data: &EventCalendar, config: &RunConfig)
+                -> Result<WindowEngine, Box<dyn Error>>
+{
+    let calendar = config.trading_calendar()?;
+    let event_dates: Vec<NaiveDate> = events.iter().map(|x| x.datetime.date()).collect();
+    let days = event_days(&event_dates, config.events.back_bdays, config.events.fwd_bdays, &calendar);
+    // Bars before the first start time are kept as lookback for direction signals
+    let bars = data.filter_days(|x| days.contains(&x));
+    info!("{} rows after filters", bars.len());
+
+    if !bars.has_spread() && config.costs.as_ref().is_some_and(|c| c.use_spread)
+    {
+        warn!("costs.use_spread is set but {} has no spread column", config.data.path);
+    }
+
+    let condition = config.context_condition(event_name, event_data, &calendar)?;
+    let releases: Vec<NaiveDateTime> = events.iter().map(|x| x.datetime).collect();
+    Ok(WindowEngine::new(bars, condition.as_ref()).with_releases(&releases))
+}
+
+fn main_routine(data: &BarSeries, event_name: &str, events: &[Event],
+                event_data: &EventCalendar, config: &RunConfig) -> Result<(), Box<dyn Error>>
 {
     // Initialize Params
-    let resolution: u64 = 1; // minutes
-    let interval_rng: Vec<u64> = (2..=60*12).filter(|x| x % resolution == 0).collect();
-    let start_time_rng: Vec<NaiveTime> = time_range((6,0,0), (16,55,0), resolution);
+    let resolution: u64 = config.sweep.resolution; // minutes
+    let interval_rng: Vec<u64> = config.sweep.interval_rng();
+    let start_time_rng: Vec<CellTime> = config.sweep.start_time_rng();
     info!("Inveral params (mins): {} to {}, by step {}", interval_rng[0], interval_rng[interval_rng.len()-1], resolution);
     info!("Start time params: {} to {}, with resolution {}", start_time_rng[0], start_time_rng[start_time_rng.len()-1], resolution);
 
     let total_runs: u64 = (interval_rng.len()*start_time_rng.len()) as u64;
     info!("Running {} times", total_runs);
 
+    let engine = event_series(data, event_name, events, event_data, config)?;
+    let spec = config.strategy_spec()?;
 
-    // Read cluster data
-    // let cluster_loc = "C:\\Users\\mbroo\\PycharmProjects\\detrending\\cluster_data.csv";
-    // let cluster = 4;
-    // let clusters: FxHashMap<i32, i32> = get_clusters(cluster_loc);//.into_iter().filter(|(k,v)| v==&cluster).collect();
-    // let cluster_dates: Vec<i32> = clusters.iter()
-    //     .filter(|(_,&v)| v==cluster)
-    //     .map(|(&k, _)| k )
-    //     .collect();
-
-    let mut v: Vec<&Row> = data
-        .iter()
-        // .filter(|x: &Row| x.datetime() >= NaiveDateTime::parse_from_str("2021-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap())
-        .filter(|&x| x.datetime().time() >= start_time_rng[0])
-        // .filter(|&x| cluster_dates.contains(&datestr_to_int(x.datetime_str.as_str())))
+    info!("Starting analysis on {} thread(s)", config.threads);
+    let now = Instant::now();
+    // One run of neighbouring intervals per thread, logging and leaving out any that fail
+    let counter = Arc::new(Mutex::new(0_u64));
+    let chunk_len = interval_rng.len().div_ceil(config.threads);
+    let results: Vec<StrategyResult> = interval_rng.par_chunks(chunk_len)
+        .map(|intervals| (intervals, run_analysis(&engine, intervals, &start_time_rng, &spec, Arc::clone(&counter),
+                                                  total_runs).map_err(|e| e.to_string())))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .flat_map(|(intervals, res)| res.unwrap_or_else(|e| {
+            error!("{} intervals {} to {}: {}", event_name, intervals[0], intervals[intervals.len() - 1], e);
+            Vec::new()
+        }))
         .collect();
+    info!("{} seconds to run,", now.elapsed().as_secs());
+    info!("for a total of {} rows", results.len());
 
-    let event_dates: Vec<NaiveDate> = events.iter().map(|dt| dt.date()).collect();
-    v = filter_timeseries_by_events(v,
-                                    &event_dates,
-                                    1, 1);
-    info!("{} rows after filters", v.len());
-
-    let datetimes: Vec<NaiveDateTime> = v.iter().map(|x| x.datetime()).collect();
-    let values: Vec<f64> = v.iter().map(|x| x.close).collect();
-    // let event_name = "Inflation Rate YoY";
+    if let Err(e) = fs::create_dir_all(&config.output.dir)
+    {
+        error!("{e}");
+    }
 
-    let mut context_conditions: Vec<Vec<bool>> = Vec::new();
-    context_conditions.push(day_of_strat(&datetimes, &vec_dates(events)));
-    // context_conditions.push(days_offset_strat(&datetimes, event_dates.get("Non Farm Payrolls").unwrap(),
-    //                                           -8, -1, true));
+    if let Err(e) = write_table(&results, &spec.field_names(), &format!("{}/{}_returns", config.output.dir, event_name.replace(' ', "_")),
+                                config.output.format)
+    {
+        error!("Write error: {}", e);
+        return Err(e)
+    }
 
-    info!("Starting analysis");
-    let is_singlethreaded: bool = match env::var("IS_SINGLETHREADED")
+    if let Some(significance) = &config.significance
     {
-        Ok(x) => {if x=="TRUE" { true } else { false }},
-        Err(e) => { error!("{}", e); false },
-    };
+        let now = Instant::now();
+        let report = significance::analyse(&engine, &results, &spec, significance)?;
+        let s = &report.summary;
+        info!("Significance tests took {:.1}s", now.elapsed().as_secs_f32());
+        println!("{} trials, {} tested jointly over {} days: expected max sharpe {:.3}, PBO {:.3}, reality check p {:.3}, \
+                  SPA p {:.3}, best {}", s.trials, s.joint_trials, s.days, s.expected_max_sharpe, s.pbo, s.reality_check_p,
+                 s.spa_p, s.best);
+        let loc = format!("{}/{}", config.output.dir, event_name.replace(' ', "_"));
+        write_table(&report.cells, &CELL_SIGNIFICANCE_FIELD_NAMES, &format!("{}_significance", loc), config.output.format)?;
+        write_table(&vec![report.summary], &SIGNIFICANCE_SUMMARY_FIELD_NAMES,
+                    &format!("{}_significance_summary", loc), config.output.format)?;
+    }
+
+    Ok(())
+}
+
+fn walk_forward_routine(data: &BarSeries, event_name: &str, events: &[Event],
+                        event_data: &EventCalendar, config: &RunConfig) -> Result<(), Box<dyn Error>>
+{
+    let engine = event_series(data, event_name, events, event_data, config)?;
+    let spec = config.strategy_spec()?;
+    let mut event_dates: Vec<NaiveDate> = events.iter().map(|x| x.datetime.date()).collect();
+    event_dates.sort();
+    event_dates.dedup();
 
     let now = Instant::now();
-    let mut results: Vec<StrategyResult> = Vec::new();
-    if !is_singlethreaded
+    let report = walk_forward(&engine, &config.sweep.interval_rng(), &config.sweep.start_time_rng(), &spec,
+                              &config.walk_forward, &event_dates)?;
+    info!("{} folds in {:.1}s", report.folds.last().map_or(0, |x| x.fold), now.elapsed().as_secs_f32());
+    for r in report.out_of_sample.iter()
     {
-        let n_threads = 12;
-        info!("Running multi({})-threaded", n_threads);
-        let mut interval_rng_: Vec<Vec<u64>> = (0..n_threads).map(|_| Vec::new() ).collect();
-        for &i in interval_rng.iter()
-        {
-            interval_rng_[(i % n_threads as u64) as usize].push(i)
-        }
+        println!("Rank {} out of sample over {} folds: {}", r.rank, r.n_folds,
+                 spec.metrics.metric_names(spec.costs.is_some()).iter().zip(r.metrics.iter())
+                     .map(|(name, x)| format!("{} {:.3}", name, x)).collect::<Vec<String>>().join(", "));
+    }
 
-        let counter = Arc::new(Mutex::new(0_u64));
-        let mut handles = vec![];
+    fs::create_dir_all(&config.output.dir)?;
+    let loc = format!("{}/{}", config.output.dir, event_name.replace(' ', "_"));
+    write_table(&report.folds, &WalkForwardReport::fold_field_names(&spec, &config.walk_forward),
+                &format!("{}_walk_forward_folds", loc), config.output.format)?;
+    write_table(&report.out_of_sample, &WalkForwardReport::out_of_sample_field_names(&spec),
+                &format!("{}_walk_forward", loc), config.output.format)?;
+    Ok(())
+}
 
-        for i in 0..interval_rng_.len()
-        {
-            let datetimes_ = datetimes.clone();
-            let values_ = values.clone();
-            let start_time_rng_: Vec<NaiveTime> = start_time_rng.clone();
-            let interval_rng_i_: Vec<u64> = interval_rng_[i].clone();
-            let context_conditions_ = context_conditions.clone();
-            let counter = Arc::clone(&counter);
-
-            let handle = thread::Builder::new().name(i.to_string()).spawn(move ||
-                {
-                run_analysis(&datetimes_, &values_, &interval_rng_i_, &start_time_rng_,
-                             counter, total_runs, &context_conditions_).unwrap_or_default()
-                }
-            );
-            handles.push(handle.unwrap());
-        }
-        results = handles.into_iter().map(|h| h.join().unwrap()).flatten().collect();
-    }
-    else if is_singlethreaded
+fn surprise_routine(data: &BarSeries, event_name: &str, events: &[Event], event_data: &EventCalendar,
+                    config: &RunConfig) -> Result<(), Box<dyn Error>>
+{
+    let n_surprises = events.iter().filter(|x| x.surprise().is_some()).count();
+    if n_surprises < 3
     {
-        // Single-threaded for profiling
-        info!("Running single-threaded");
-        results = run_analysis(&datetimes, &values, &interval_rng, &start_time_rng,
-                               Arc::new(Mutex::new(0)), total_runs, &context_conditions).unwrap();
+        return Err(format!("{} releases have actual and forecast values, too few to regress on", n_surprises).into())
     }
-    info!("{} seconds to run,", now.elapsed().as_secs());
-    info!("for a total of {} rows", results.len());
+    let engine = event_series(data, event_name, events, event_data, config)?;
+    let spec = config.strategy_spec()?;
+
+    let now = Instant::now();
+    let results = surprise_regressions(&engine, &config.sweep.interval_rng(), &config.sweep.start_time_rng(), &spec,
+                                       events);
+    info!("{} cells regressed on {} surprises in {:.1}s", results.len(), n_surprises, now.elapsed().as_secs_f32());
+
+    fs::create_dir_all(&config.output.dir)?;
+    write_table(&results, &SURPRISE_FIELD_NAMES,
+                &format!("{}/{}_surprise", config.output.dir, event_name.replace(' ', "_")), config.output.format)?;
+    Ok(())
+}
+
+fn run_single(config: &RunConfig, event_name: &str, interval: u64, start_time: CellTime,
+              direction: Direction, exit_rule: &ExitRule) -> Result<(), Box<dyn Error>>
+{
+    let data = load_exchange_data(config)?;
+    let event_data: EventCalendar = load_calendar(config)?;
+    let events = event_data.get(event_name)
+        .ok_or_else(|| format!("Event {} not found in {}", event_name, config.events.path))?;
+
+    let engine = event_series(&data, event_name, events, &event_data, config)?;
+    let spec = StrategySpec { directions: vec![direction], exit_rules: vec![*exit_rule], ..config.strategy_spec()? };
+    // A cell with too few trades has no aggregate row, but its ledger is still worth writing
+    let results = run_analysis(&engine, &[interval], &[start_time], &spec, Arc::new(Mutex::new(0)), 1)
+        .unwrap_or_default();
+    let trades = run_trades(&engine, interval, &start_time, direction, exit_rule, &spec);
 
-    match fs::create_dir(output_path)
+    println!("{}", spec.field_names().join(","));
+    for r in results.iter()
     {
-        Ok(()) => (),
-        Err(e) => error!("{e}"),
+        println!("{}", r.fields_to_strings().join(","));
     }
+    println!("{} trades", trades.len());
 
-    match write_csv(&results, &FIELD_NAMES, format!("{}/{}_returns.csv", output_path, event_name.replace(" ", "_")).as_str())
+    fs::create_dir_all(&config.output.dir)?;
+    let start = match start_time {
+        CellTime::Clock(t) => t.format("%H%M").to_string(),
+        t => t.to_string(),
+    };
+    let mut loc = format!("{}/{}_{}_{}_{}", config.output.dir, event_name.replace(' ', "_"), start, interval,
+                          direction.to_string().replace(':', ""));
+    if !exit_rule.is_none()
     {
-        Err(e) => {error!("Write CSV error: {}", e); return Err(e) },
-        _ => (),
+        loc = format!("{}_{}", loc, exit_rule.to_string().replace(' ', "_").replace('=', "").replace('%', "pct"));
     }
+    if !results.is_empty()
+    {
+        write_table(&results, &spec.field_names(), &format!("{}_run", loc), config.output.format)?;
+    }
+    if !trades.is_empty()
+    {
+        write_table(&trades, &TRADE_FIELD_NAMES, &format!("{}_trades", loc), config.output.format)?;
+    }
+    Ok(())
+}
 
-    // std::process::Command::new("cmd")
-    //     .args(&["/C", "C:/Users/mbroo/anaconda3/envs/backtesting/python.exe C:/Users/mbroo/PycharmProjects/backtesting/from_rust.py"])
-    //     .output()
-    //     .expect("failed to execute process");
+fn list_events(config: &RunConfig) -> Result<(), Box<dyn Error>>
+{
+    let event_data: EventCalendar = load_calendar(config)?;
+    let mut names: Vec<&String> = event_data.keys().collect();
+    names.sort();
 
+    println!("{:<50} {:>6} {:>12} {:>12}", "event", "count", "first", "last");
+    for name in names
+    {
+        let dts: Vec<NaiveDateTime> = event_data[name].iter().map(|x| x.datetime).collect();
+        let first = dts.iter().min().map(|x| x.date().to_string()).unwrap_or_default();
+        let last = dts.iter().max().map(|x| x.date().to_string()).unwrap_or_default();
+        println!("{:<50} {:>6} {:>12} {:>12}", name, dts.len(), first, last);
+    }
+    println!("{} events, {} releases", event_data.len(), event_data.values().map(|x| x.len()).sum::<usize>());
     Ok(())
 }
 
+fn inspect(config: &RunConfig) -> Result<(), Box<dyn Error>>
+{
+    let (data, quality) = load_data(config)?;
+    if data.is_empty()
+    {
+        println!("{}: no rows", config.data.path);
+        return Ok(())
+    }
+
+    let n_days = data.days().len();
+    let closes: Vec<f64> = data.close().to_vec();
+    let min_close = closes.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max_close = closes.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+
+    println!("file:        {}", config.data.path);
+    println!("rows:        {}", data.len());
+    println!("range:       {} to {}", data.datetime(0), data.datetime(data.len() - 1));
+    println!("days:        {} ({:.0} rows/day)", n_days, data.len() as f64 / n_days as f64);
+    println!("close:       min {} max {} mean {:.4}", min_close, max_close, vec_mean(&closes).unwrap_or(f64::NAN));
+    println!("volume:      total {}", data.volume().iter().sum::<f64>());
+    println!("quality:     {} ({} of {} rows dropped)", quality.summary(), quality.dropped, quality.rows);
+    Ok(())
+}
@@ -0,0 +1,342 @@
+use std::error::Error;
+use simple_error::SimpleError;
+use crate::utils::comp_f64;
+use crate::vector_utils::{vec_mean, vec_std};
+
+/// Default trading days per year used to annualize per-trade statistics
+pub const TRADING_DAYS: f64 = 252.;
+
+/// Per-trade observations for one parameter cell, in chronological order. Excursions are measured
+/// from the entry price, `drawups` being the lowest and `drawdowns` the highest point of each trade.
+#[derive(Default)]
+pub struct Observations
+{
+    pub returns: Vec<f64>,
+    pub drawups: Vec<f64>,
+    pub drawdowns: Vec<f64>,
+}
+
+impl Observations
+{
+    pub fn push(&mut self, ret: f64, drawup: f64, drawdown: f64)
+    {
+        self.returns.push(ret);
+        self.drawups.push(drawup);
+        self.drawdowns.push(drawdown);
+    }
+
+    /// Appends `other`'s observations after these
+    pub fn append(&mut self, other: &Observations)
+    {
+        self.returns.extend_from_slice(&other.returns);
+        self.drawups.extend_from_slice(&other.drawups);
+        self.drawdowns.extend_from_slice(&other.drawdowns);
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.returns.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.returns.is_empty()
+    }
+}
+
+pub trait Metric: Send + Sync
+{
+    /// Identifier used in run configs, written to CSV headers with underscores as spaces
+    fn name(&self) -> &'static str;
+    fn compute(&self, obs: &Observations) -> f64;
+}
+
+/// Ordered selection of metrics computed for every parameter cell
+pub struct MetricSet
+{
+    metrics: Vec<Box<dyn Metric>>,
+}
+
+pub const DEFAULT_METRICS: [&str; 3] = ["sharpe", "max_drawup", "max_drawdown"];
+
+impl MetricSet
+{
+    /// Metrics by name, annualizing over `trading_days` per year
+    pub fn from_names<S: AsRef<str>>(names: &[S], trading_days: f64) -> Result<Self, Box<dyn Error>>
+    {
+        let metrics = names.iter()
+            .map(|name| metric_by_name(name.as_ref(), trading_days)
+                .ok_or_else(|| SimpleError::new(format!("Unknown metric {}", name.as_ref()))))
+            .collect::<Result<Vec<Box<dyn Metric>>, SimpleError>>()?;
+        Ok(Self { metrics })
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.metrics.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.metrics.is_empty()
+    }
+
+    pub fn compute(&self, obs: &Observations) -> Vec<f64>
+    {
+        self.metrics.iter().map(|m| m.compute(obs)).collect()
+    }
+
+    /// CSV header for `StrategyResult` rows computed with this set, with a "net" copy of each metric
+    /// when `with_net` is set
+    pub fn field_names(&self, with_net: bool) -> Vec<String>
+    {
+        let mut v: Vec<String> = vec!["interval".to_owned(), "start time".to_owned(), "end time".to_owned(),
+                                     "direction".to_owned(), "exit rule".to_owned()];
+        v.extend(self.metric_names(with_net));
+        v.push("n obs".to_owned());
+        v
+    }
+
+    /// Metric column names, underscores as spaces, followed by their "net" copies when `with_net` is set
+    pub fn metric_names(&self, with_net: bool) -> Vec<String>
+    {
+        let mut v: Vec<String> = self.metrics.iter().map(|m| m.name().replace('_', " ")).collect();
+        if with_net {
+            v.extend(self.metrics.iter().map(|m| format!("net {}", m.name().replace('_', " "))));
+        }
+        v
+    }
+
+    /// Position of a metric like `sharpe` or `net_sharpe` in the values computed with this set
+    pub fn column(&self, name: &str, with_net: bool) -> Option<usize>
+    {
+        let (name, offset) = match name.strip_prefix("net_") {
+            Some(x) if with_net => (x, self.metrics.len()),
+            Some(_) => return None,
+            None => (name, 0),
+        };
+        self.metrics.iter().position(|m| m.name() == name).map(|i| i + offset)
+    }
+}
+
+impl Default for MetricSet
+{
+    fn default() -> Self
+    {
+        Self::from_names(&DEFAULT_METRICS, TRADING_DAYS).unwrap()
+    }
+}
+
+pub fn metric_by_name(name: &str, trading_days: f64) -> Option<Box<dyn Metric>>
+{
+    let m: Box<dyn Metric> = match name
+    {
+        "sharpe" => Box::new(Sharpe { trading_days }),
+        "max_drawup" => Box::new(MaxDrawup),
+        "max_drawdown" => Box::new(MaxDrawdown),
+        "mean" => Box::new(MeanReturn),
+        "median" => Box::new(MedianReturn),
+        "hit_rate" => Box::new(HitRate),
+        "profit_factor" => Box::new(ProfitFactor),
+        "sortino" => Box::new(Sortino { trading_days }),
+        "calmar" => Box::new(Calmar { trading_days }),
+        "t_stat" => Box::new(TStat),
+        "skew" => Box::new(Skew),
+        "kurtosis" => Box::new(Kurtosis),
+        "avg_win" => Box::new(AverageWin),
+        "avg_loss" => Box::new(AverageLoss),
+        "expectancy" => Box::new(Expectancy),
+        _ => return None,
+    };
+    Some(m)
+}
+
+fn mean(v: &[f64]) -> f64
+{
+    match v.len() {
+        0 => f64::NAN,
+        n => v.iter().sum::<f64>() / n as f64,
+    }
+}
+
+/// Standardized central moment of order `k`
+pub(crate) fn moment(v: &[f64], k: i32) -> f64
+{
+    let m = mean(v);
+    let var = v.iter().map(|x| (x - m).powi(2)).sum::<f64>() / v.len() as f64;
+    v.iter().map(|x| (x - m).powi(k)).sum::<f64>() / v.len() as f64 / var.powf(k as f64 / 2.)
+}
+
+/// Annualized mean over standard deviation of trade returns
+pub struct Sharpe { pub trading_days: f64 }
+impl Metric for Sharpe
+{
+    fn name(&self) -> &'static str { "sharpe" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        vec_mean(&obs.returns).unwrap_or(f64::NAN) / vec_std(&obs.returns).unwrap_or(f64::NAN) * self.trading_days.sqrt()
+    }
+}
+
+/// Second lowest intra-trade excursion across trades
+pub struct MaxDrawup;
+impl Metric for MaxDrawup
+{
+    fn name(&self) -> &'static str { "max_drawup" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        let mut v = obs.drawups.clone();
+        v.sort_by(comp_f64);
+        v.get(1).cloned().unwrap_or(f64::NAN)
+    }
+}
+
+/// Highest intra-trade excursion across trades
+pub struct MaxDrawdown;
+impl Metric for MaxDrawdown
+{
+    fn name(&self) -> &'static str { "max_drawdown" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        obs.drawdowns.iter().cloned().reduce(f64::max).unwrap_or(f64::NAN)
+    }
+}
+
+pub struct MeanReturn;
+impl Metric for MeanReturn
+{
+    fn name(&self) -> &'static str { "mean" }
+    fn compute(&self, obs: &Observations) -> f64 { mean(&obs.returns) }
+}
+
+pub struct MedianReturn;
+impl Metric for MedianReturn
+{
+    fn name(&self) -> &'static str { "median" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        let mut v = obs.returns.clone();
+        v.sort_by(comp_f64);
+        match v.len() {
+            0 => f64::NAN,
+            n if n % 2 == 1 => v[n / 2],
+            n => (v[n / 2 - 1] + v[n / 2]) / 2.,
+        }
+    }
+}
+
+/// Fraction of trades with a positive return
+pub struct HitRate;
+impl Metric for HitRate
+{
+    fn name(&self) -> &'static str { "hit_rate" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        obs.returns.iter().filter(|&&x| x > 0.).count() as f64 / obs.len() as f64
+    }
+}
+
+/// Gross profit over gross loss
+pub struct ProfitFactor;
+impl Metric for ProfitFactor
+{
+    fn name(&self) -> &'static str { "profit_factor" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        let wins: f64 = obs.returns.iter().filter(|&&x| x > 0.).sum();
+        let losses: f64 = obs.returns.iter().filter(|&&x| x < 0.).sum();
+        wins / -losses
+    }
+}
+
+/// Annualized mean over downside deviation, with a zero target
+pub struct Sortino { pub trading_days: f64 }
+impl Metric for Sortino
+{
+    fn name(&self) -> &'static str { "sortino" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        let downside = obs.returns.iter().map(|x| x.min(0.).powi(2)).sum::<f64>() / obs.len() as f64;
+        mean(&obs.returns) / downside.sqrt() * self.trading_days.sqrt()
+    }
+}
+
+/// Annualized mean return over the maximum drawdown of the cumulative trade returns
+pub struct Calmar { pub trading_days: f64 }
+impl Metric for Calmar
+{
+    fn name(&self) -> &'static str { "calmar" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        let (mut equity, mut peak, mut max_dd) = (0_f64, 0_f64, 0_f64);
+        for r in obs.returns.iter() {
+            equity += r;
+            peak = peak.max(equity);
+            max_dd = max_dd.max(peak - equity);
+        }
+        mean(&obs.returns) * self.trading_days / max_dd
+    }
+}
+
+/// t-statistic of the mean return against zero
+pub struct TStat;
+impl Metric for TStat
+{
+    fn name(&self) -> &'static str { "t_stat" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        vec_mean(&obs.returns).unwrap_or(f64::NAN) / vec_std(&obs.returns).unwrap_or(f64::NAN) * (obs.len() as f64).sqrt()
+    }
+}
+
+pub struct Skew;
+impl Metric for Skew
+{
+    fn name(&self) -> &'static str { "skew" }
+    fn compute(&self, obs: &Observations) -> f64 { moment(&obs.returns, 3) }
+}
+
+/// Excess kurtosis
+pub struct Kurtosis;
+impl Metric for Kurtosis
+{
+    fn name(&self) -> &'static str { "kurtosis" }
+    fn compute(&self, obs: &Observations) -> f64 { moment(&obs.returns, 4) - 3. }
+}
+
+pub struct AverageWin;
+impl Metric for AverageWin
+{
+    fn name(&self) -> &'static str { "avg_win" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        mean(&obs.returns.iter().cloned().filter(|&x| x > 0.).collect::<Vec<f64>>())
+    }
+}
+
+pub struct AverageLoss;
+impl Metric for AverageLoss
+{
+    fn name(&self) -> &'static str { "avg_loss" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        mean(&obs.returns.iter().cloned().filter(|&x| x < 0.).collect::<Vec<f64>>())
+    }
+}
+
+/// Hit rate times average win plus loss rate times average loss
+pub struct Expectancy;
+impl Metric for Expectancy
+{
+    fn name(&self) -> &'static str { "expectancy" }
+    fn compute(&self, obs: &Observations) -> f64
+    {
+        let hit_rate = HitRate.compute(obs);
+        let loss_rate = obs.returns.iter().filter(|&&x| x < 0.).count() as f64 / obs.len() as f64;
+        let avg_win = AverageWin.compute(obs);
+        let avg_loss = AverageLoss.compute(obs);
+        let win_part = if hit_rate > 0. { hit_rate * avg_win } else { 0. };
+        let loss_part = if loss_rate > 0. { loss_rate * avg_loss } else { 0. };
+        win_part + loss_part
+    }
+}
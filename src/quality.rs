@@ -0,0 +1,292 @@
+use std::error::Error;
+use std::fmt;
+use chrono::NaiveDateTime;
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use serde_derive::Deserialize;
+use simple_error::SimpleError;
+use crate::bars::{from_timestamp, BarSeries};
+use crate::loader::InvalidRow;
+use crate::strategy::FieldsToStrings;
+use crate::utils::write_csv;
+
+/// Kinds of problem the data quality checks look for
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind
+{
+    /// A row with a missing or malformed timestamp or price
+    Unparseable,
+    /// A bar stamped at the same time as the one before it
+    Duplicate,
+    /// A bar stamped before the one before it
+    NonMonotonic,
+    /// A gap within a day's session longer than the usual bar spacing
+    MissingMinutes,
+    /// An open, high, low or close of zero or less
+    NonPositivePrice,
+    /// High below low, or open or close outside the low to high range
+    InvalidRange,
+    /// A close-to-close move larger than `max_jump`
+    Jump,
+}
+
+pub static ISSUE_KINDS: [IssueKind; 7] = [IssueKind::Unparseable, IssueKind::Duplicate, IssueKind::NonMonotonic,
+                                          IssueKind::MissingMinutes, IssueKind::NonPositivePrice,
+                                          IssueKind::InvalidRange, IssueKind::Jump];
+
+impl fmt::Display for IssueKind
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.write_str(match self {
+            IssueKind::Unparseable => "unparseable",
+            IssueKind::Duplicate => "duplicate",
+            IssueKind::NonMonotonic => "non_monotonic",
+            IssueKind::MissingMinutes => "missing_minutes",
+            IssueKind::NonPositivePrice => "non_positive_price",
+            IssueKind::InvalidRange => "invalid_range",
+            IssueKind::Jump => "jump",
+        })
+    }
+}
+
+/// What to do about an issue: stop the run, leave out the bars with it, or only report it
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy
+{
+    Fail,
+    Drop,
+    #[default]
+    Warn,
+}
+
+/// Data quality checks run on the bars as loaded. Unparseable rows can't be kept, and the engine
+/// needs bars in time order, so warn leaves out unparseable, duplicate and non-monotonic bars like
+/// drop does. Missing minutes can't be dropped.
+#[derive(Deserialize, Debug, Clone)]
+pub struct QualityConfig
+{
+    /// Policy for issue kinds `checks` doesn't name
+    #[serde(default)]
+    pub policy: Policy,
+    /// Policies by issue kind, overriding `policy`
+    #[serde(default)]
+    pub checks: FxHashMap<IssueKind, Policy>,
+    /// Largest close-to-close move, as a fraction of the previous close, that isn't a jump
+    #[serde(default = "default_max_jump")]
+    pub max_jump: f64,
+    /// Gaps within a day longer than this are session breaks rather than missing minutes
+    #[serde(default = "default_max_gap_minutes")]
+    pub max_gap_minutes: u64,
+    /// CSV every issue is written to
+    #[serde(default)]
+    pub report: Option<String>,
+}
+
+fn default_max_jump() -> f64 { 0.05 }
+fn default_max_gap_minutes() -> u64 { 30 }
+
+impl Default for QualityConfig
+{
+    fn default() -> Self
+    {
+        Self { policy: Policy::default(), checks: FxHashMap::default(), max_jump: default_max_jump(),
+               max_gap_minutes: default_max_gap_minutes(), report: None }
+    }
+}
+
+impl QualityConfig
+{
+    pub fn policy(&self, kind: IssueKind) -> Policy
+    {
+        self.checks.get(&kind).cloned().unwrap_or(self.policy)
+    }
+
+    /// Whether bars with an issue of `kind` are left out
+    fn drops(&self, kind: IssueKind) -> bool
+    {
+        match self.policy(kind) {
+            Policy::Drop => true,
+            Policy::Warn => matches!(kind, IssueKind::Unparseable | IssueKind::Duplicate | IssueKind::NonMonotonic),
+            Policy::Fail => false,
+        }
+    }
+
+    pub fn validate(&self) -> Vec<String>
+    {
+        let mut errors: Vec<String> = Vec::new();
+        if self.max_jump.is_nan() || self.max_jump <= 0. || self.max_gap_minutes == 0 {
+            errors.push("data.quality max_jump and max_gap_minutes must be positive".to_owned());
+        }
+        if self.policy(IssueKind::MissingMinutes) == Policy::Drop {
+            errors.push("data.quality can't drop missing_minutes, only fail or warn".to_owned());
+        }
+        errors
+    }
+}
+
+/// One problem found in the data, at a bar's time or a file line
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityIssue
+{
+    pub kind: IssueKind,
+    pub datetime: Option<NaiveDateTime>,
+    pub line: Option<u64>,
+    pub detail: String,
+}
+
+pub static QUALITY_FIELD_NAMES: [&str; 4] = ["kind", "datetime", "line", "detail"];
+
+impl FieldsToStrings for QualityIssue
+{
+    fn fields_to_strings(&self) -> Vec<String>
+    {
+        vec![self.kind.to_string(), self.datetime.map_or(String::new(), |x| x.to_string()),
+             self.line.map_or(String::new(), |x| x.to_string()), self.detail.clone()]
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QualityReport
+{
+    /// Bars loaded, before any were dropped
+    pub rows: usize,
+    pub dropped: usize,
+    pub issues: Vec<QualityIssue>,
+}
+
+impl QualityReport
+{
+    pub fn count(&self, kind: IssueKind) -> usize
+    {
+        self.issues.iter().filter(|x| x.kind == kind).count()
+    }
+
+    /// Counts of each kind of issue found, like "2 duplicate, 1 jump"
+    pub fn summary(&self) -> String
+    {
+        let counts: Vec<String> = ISSUE_KINDS.iter()
+            .map(|&kind| (kind, self.count(kind)))
+            .filter(|(_, n)| *n > 0)
+            .map(|(kind, n)| format!("{} {}", n, kind))
+            .collect();
+        if counts.is_empty() { "no issues".to_owned() } else { counts.join(", ") }
+    }
+}
+
+/// Checks each bar against the one kept before it and each day for gaps, leaving out the bars with
+/// an issue whose policy is drop, and out of order bars unless failing on them. Jumps are measured
+/// from the last close that was kept and valid, and gaps are found after dropping, against the most
+/// common spacing between bars.
+pub fn check_bars(bars: BarSeries, invalid: Vec<InvalidRow>, config: &QualityConfig) -> (BarSeries, QualityReport)
+{
+    let mut issues: Vec<QualityIssue> = invalid.into_iter()
+        .map(|x| QualityIssue { kind: IssueKind::Unparseable, datetime: None, line: Some(x.line), detail: x.reason })
+        .collect();
+    let mut keep = vec![true; bars.len()];
+    let mut latest: Option<i64> = None;
+    let mut prev_close: Option<f64> = None;
+    for (i, &t) in bars.timestamps().iter().enumerate() {
+        let mut found: Vec<(IssueKind, String)> = Vec::new();
+        match latest {
+            Some(x) if t == x => found.push((IssueKind::Duplicate, "repeats the previous bar's time".to_owned())),
+            Some(x) if t < x => found.push((IssueKind::NonMonotonic, format!("before {}", from_timestamp(x)))),
+            _ => {},
+        }
+        let (open, high, low, close) = (bars.open()[i], bars.high()[i], bars.low()[i], bars.close()[i]);
+        let valid_prices = [open, high, low, close].iter().all(|&x| x > 0.);
+        if !valid_prices {
+            found.push((IssueKind::NonPositivePrice, format!("open {} high {} low {} close {}", open, high, low, close)));
+        }
+        if high < low {
+            found.push((IssueKind::InvalidRange, format!("high {} below low {}", high, low)));
+        } else if let Some((name, x)) = [("close", close), ("open", open)].into_iter().find(|(_, x)| !(low..=high).contains(x)) {
+            found.push((IssueKind::InvalidRange, format!("{} {} outside low {} high {}", name, x, low, high)));
+        }
+        if let Some(prev) = prev_close.filter(|_| valid_prices) {
+            let change = close / prev - 1.;
+            if change.abs() > config.max_jump {
+                found.push((IssueKind::Jump, format!("close moved {:.2}% from {}", change * 100., prev)));
+            }
+        }
+
+        if found.iter().any(|(kind, _)| config.drops(*kind)) {
+            keep[i] = false;
+        } else {
+            latest = Some(latest.map_or(t, |x| x.max(t)));
+            if valid_prices {
+                prev_close = Some(close);
+            }
+        }
+        issues.extend(found.into_iter()
+            .map(|(kind, detail)| QualityIssue { kind, datetime: Some(bars.datetime(i)), line: None, detail }));
+    }
+
+    let rows = bars.len();
+    let dropped = keep.iter().filter(|x| !**x).count();
+    let bars = if dropped > 0 { bars.filter_rows(|i| keep[i]) } else { bars };
+    issues.extend(missing_minutes(&bars, config.max_gap_minutes));
+    (bars, QualityReport { rows, dropped, issues })
+}
+
+/// Gaps within each day longer than the most common spacing between bars and up to `max_gap_minutes`.
+/// Gaps run from the latest bar so far, so bars kept out of order don't open one.
+fn missing_minutes(bars: &BarSeries, max_gap_minutes: u64) -> Vec<QualityIssue>
+{
+    let timestamps = bars.timestamps();
+    let mut steps: Vec<(usize, i64, i64)> = Vec::new();
+    for d in 0..bars.days().len() {
+        let rows = bars.day_range(d);
+        let mut latest = timestamps[rows.start];
+        for (i, &t) in timestamps.iter().enumerate().take(rows.end).skip(rows.start + 1) {
+            if t > latest {
+                steps.push((i, latest, t - latest));
+                latest = t;
+            }
+        }
+    }
+    let mut counts: FxHashMap<i64, usize> = FxHashMap::default();
+    for &(_, _, step) in steps.iter() {
+        *counts.entry(step).or_insert(0) += 1;
+    }
+    let spacing = match counts.into_iter().max_by_key(|&(step, n)| (n, -step)) {
+        Some((step, _)) => step,
+        None => return Vec::new(),
+    };
+    steps.into_iter()
+        .filter(|&(_, _, step)| step > spacing && step <= max_gap_minutes as i64 * 60)
+        .map(|(i, from, step)| QualityIssue {
+            kind: IssueKind::MissingMinutes,
+            datetime: Some(bars.datetime(i)),
+            line: None,
+            detail: format!("{} minutes missing after {}", (step - spacing) / 60, from_timestamp(from).time()),
+        })
+        .collect()
+}
+
+/// Runs the checks, logs what they found, writes the report if one is configured, and fails when
+/// any issue found has the fail policy
+pub fn validate_bars(bars: BarSeries, invalid: Vec<InvalidRow>, config: &QualityConfig)
+                     -> Result<(BarSeries, QualityReport), Box<dyn Error>>
+{
+    let (bars, report) = check_bars(bars, invalid, config);
+    if report.issues.is_empty() {
+        info!("Data quality: no issues in {} bars", report.rows);
+        return Ok((bars, report))
+    }
+    warn!("Data quality: {} in {} bars, {} dropped", report.summary(), report.rows, report.dropped);
+    if let Some(loc) = &config.report {
+        write_csv(&report.issues, &QUALITY_FIELD_NAMES, loc)?;
+    }
+    let failed: Vec<String> = ISSUE_KINDS.iter()
+        .filter(|&&kind| config.policy(kind) == Policy::Fail && report.count(kind) > 0)
+        .map(|kind| kind.to_string())
+        .collect();
+    if !failed.is_empty() {
+        return Err(Box::new(SimpleError::new(format!("Data quality check failed on {}: {}", failed.join(", "),
+                                                     report.summary()))))
+    }
+    Ok((bars, report))
+}
@@ -0,0 +1,124 @@
+use chrono::NaiveDate;
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use crate::analysis::{run_trades, window_end_time};
+use crate::events::Event;
+use crate::exits::ExitRule;
+use crate::strategy::{metric_field_types, Direction, FieldType, FieldValue, FieldsToStrings, FieldsToValues, StrategySpec,
+                      CELL_KEY_TYPES};
+use crate::window::{CellTime, WindowEngine};
+
+/// Least squares fit of a cell's trade returns on the surprise of the release each trade's day
+pub struct SurpriseRegression
+{
+    pub interval: u64,
+    pub start_time: CellTime,
+    pub end_time: CellTime,
+    pub direction: Direction,
+    pub exit_rule: ExitRule,
+    /// Return per unit of actual less forecast
+    pub beta: f64,
+    pub t_stat: f64,
+    pub r_squared: f64,
+    /// Share of trades on days with a nonzero surprise whose return has the surprise's sign
+    pub sign_agreement: f64,
+    pub n_obs: usize,
+}
+
+pub static SURPRISE_FIELD_NAMES: [&str; 10] = ["interval", "start time", "end time", "direction", "exit rule",
+                                               "beta", "t stat", "r squared", "sign agreement", "n obs"];
+
+impl FieldsToStrings for SurpriseRegression
+{
+    fn fields_to_strings(&self) -> Vec<String>
+    {
+        vec![self.interval.to_string(), self.start_time.to_string(), self.end_time.to_string(),
+             self.direction.to_string(), self.exit_rule.to_string(), self.beta.to_string(), self.t_stat.to_string(),
+             self.r_squared.to_string(), self.sign_agreement.to_string(), self.n_obs.to_string()]
+    }
+}
+
+impl FieldsToValues for SurpriseRegression
+{
+    fn field_types(n: usize) -> Vec<FieldType>
+    {
+        metric_field_types(&CELL_KEY_TYPES, n)
+    }
+
+    fn fields_to_values(&self) -> Vec<FieldValue>
+    {
+        vec![self.interval.into(), self.start_time.to_string().into(), self.end_time.to_string().into(),
+             self.direction.to_string().into(), self.exit_rule.to_string().into(), self.beta.into(),
+             self.t_stat.into(), self.r_squared.into(), self.sign_agreement.into(), self.n_obs.into()]
+    }
+}
+
+/// Regresses every cell of the grid on the surprises of `events`, across the rayon thread pool.
+/// Trades on days without a known surprise are left out, and cells need three trades whose
+/// surprises and returns differ, and don't lie on a line, to be fitted.
+pub fn surprise_regressions(engine: &WindowEngine, interval_rng: &[u64], start_time_rng: &[CellTime],
+                            spec: &StrategySpec, events: &[Event]) -> Vec<SurpriseRegression>
+{
+    let mut surprises: FxHashMap<NaiveDate, f64> = FxHashMap::default();
+    for e in events.iter() {
+        if let Some(s) = e.surprise() {
+            surprises.entry(e.datetime.date()).or_insert(s);
+        }
+    }
+    let surprises = &surprises;
+    interval_rng.par_iter()
+        .flat_map_iter(|&interval| start_time_rng.iter()
+            .flat_map(move |start_time| regress_cell(engine, interval, start_time, spec, surprises)))
+        .collect()
+}
+
+/// Regressions for every exit rule and direction of one interval/start time cell
+fn regress_cell(engine: &WindowEngine, interval: u64, start_time: &CellTime, spec: &StrategySpec,
+                surprises: &FxHashMap<NaiveDate, f64>) -> Vec<SurpriseRegression>
+{
+    let end_time = match window_end_time(start_time, interval, &spec.contract) {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+    let cells = spec.exit_rules.iter().flat_map(|rule| spec.directions.iter().map(move |direction| (rule, direction)));
+    cells
+        .filter_map(|(rule, direction)| {
+            let (x, y): (Vec<f64>, Vec<f64>) = run_trades(engine, interval, start_time, *direction, rule, spec)
+                .iter()
+                .filter_map(|t| Some((*surprises.get(&t.entry_datetime.date())?, t.ret)))
+                .unzip();
+            let (beta, t_stat, r_squared) = ols(&x, &y)?;
+            let agrees: Vec<bool> = x.iter().zip(y.iter()).filter(|(x, _)| **x != 0.).map(|(x, y)| x * y > 0.).collect();
+            Some(SurpriseRegression {
+                interval,
+                start_time: *start_time,
+                end_time,
+                direction: *direction,
+                exit_rule: *rule,
+                beta,
+                t_stat,
+                r_squared,
+                sign_agreement: agrees.iter().filter(|x| **x).count() as f64 / agrees.len() as f64,
+                n_obs: x.len(),
+            })
+        })
+        .collect()
+}
+
+/// Slope, its t-statistic and R² of `y` on `x` with an intercept, or `None` with fewer than three
+/// points, no spread in `x` or `y`, or points on an exact line, where R² or the t-statistic aren't finite
+pub fn ols(x: &[f64], y: &[f64]) -> Option<(f64, f64, f64)>
+{
+    let n = x.len() as f64;
+    if x.len() < 3 { return None }
+    let (x_mean, y_mean) = (x.iter().sum::<f64>() / n, y.iter().sum::<f64>() / n);
+    let sxx: f64 = x.iter().map(|x| (x - x_mean).powi(2)).sum();
+    let sxy: f64 = x.iter().zip(y.iter()).map(|(x, y)| (x - x_mean) * (y - y_mean)).sum();
+    let syy: f64 = y.iter().map(|y| (y - y_mean).powi(2)).sum();
+    if sxx <= 0. || syy <= 0. { return None }
+    let beta = sxy / sxx;
+    let ssr = syy - beta * sxy;
+    if ssr <= syy * f64::EPSILON { return None }
+    let t_stat = beta / (ssr / (n - 2.) / sxx).sqrt();
+    Some((beta, t_stat, 1. - ssr / syy))
+}
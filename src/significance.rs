@@ -0,0 +1,408 @@
+use std::error::Error;
+use log::{info, warn};
+use chrono::NaiveDate;
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde_derive::Deserialize;
+use simple_error::SimpleError;
+use crate::analysis::run_trades;
+use crate::bootstrap::stationary_bootstrap;
+use crate::exits::ExitRule;
+use crate::metrics::moment;
+use crate::strategy::{metric_field_types, Direction, FieldType, FieldValue, FieldsToStrings, FieldsToValues,
+                      StrategyResult, StrategySpec, CELL_KEY_TYPES};
+use crate::window::{CellTime, WindowEngine};
+
+const EULER_MASCHERONI: f64 = 0.5772156649015329;
+
+/// Multiple-testing post-processing of a sweep's reported cells
+#[derive(Deserialize, Debug, Clone)]
+pub struct SignificanceConfig
+{
+    /// Stationary bootstrap resamples for the Reality Check and SPA tests
+    #[serde(default = "default_bootstrap_samples")]
+    pub bootstrap_samples: usize,
+    /// Mean block length, in trading days, of the stationary bootstrap
+    #[serde(default = "default_block_length")]
+    pub block_length: f64,
+    /// Even number of day blocks combinatorially symmetric cross-validation splits the sample into
+    #[serde(default = "default_partitions")]
+    pub partitions: usize,
+    /// Test returns net of costs instead of gross, when costs are modelled
+    #[serde(default)]
+    pub net: bool,
+    #[serde(default)]
+    pub seed: u64,
+    /// Cells with the highest trade Sharpe ratios given the joint PBO, Reality Check and SPA tests;
+    /// 0 tests every cell
+    #[serde(default = "default_top_n")]
+    pub top_n: usize,
+}
+
+fn default_top_n() -> usize { 500 }
+fn default_bootstrap_samples() -> usize { 1000 }
+fn default_block_length() -> f64 { 5. }
+fn default_partitions() -> usize { 10 }
+
+impl SignificanceConfig
+{
+    pub fn validate(&self) -> Vec<String>
+    {
+        let mut errors: Vec<String> = Vec::new();
+        if self.bootstrap_samples == 0 || self.block_length < 1. {
+            errors.push("significance needs bootstrap_samples > 0 and block_length >= 1".to_owned());
+        }
+        if self.partitions < 2 || !self.partitions.is_multiple_of(2) {
+            errors.push(format!("significance partitions {} must be even and at least 2", self.partitions));
+        }
+        errors
+    }
+}
+
+/// Per-cell significance, with Sharpe ratios per trade rather than annualized
+pub struct CellSignificance
+{
+    pub interval: u64,
+    pub start_time: CellTime,
+    pub end_time: CellTime,
+    pub direction: Direction,
+    pub exit_rule: ExitRule,
+    pub sharpe: f64,
+    /// Probability the true Sharpe ratio is above zero, allowing for skew and fat tails
+    pub psr: f64,
+    /// Probability the true Sharpe ratio is above the best expected from this many trials by chance
+    pub deflated_sharpe: f64,
+    pub p_value: f64,
+    /// Benjamini-Hochberg false discovery rate adjusted p-value across all cells
+    pub adjusted_p_value: f64,
+    pub n_obs: usize,
+}
+
+pub static CELL_SIGNIFICANCE_FIELD_NAMES: [&str; 11] = ["interval", "start time", "end time", "direction",
+                                                        "exit rule", "trade sharpe", "psr", "deflated sharpe",
+                                                        "p value", "bh p value", "n obs"];
+
+impl FieldsToStrings for CellSignificance
+{
+    fn fields_to_strings(&self) -> Vec<String>
+    {
+        vec![self.interval.to_string(), self.start_time.to_string(), self.end_time.to_string(),
+             self.direction.to_string(), self.exit_rule.to_string(), self.sharpe.to_string(), self.psr.to_string(),
+             self.deflated_sharpe.to_string(), self.p_value.to_string(), self.adjusted_p_value.to_string(),
+             self.n_obs.to_string()]
+    }
+}
+
+impl FieldsToValues for CellSignificance
+{
+    fn field_types(n: usize) -> Vec<FieldType>
+    {
+        metric_field_types(&CELL_KEY_TYPES, n)
+    }
+
+    fn fields_to_values(&self) -> Vec<FieldValue>
+    {
+        vec![self.interval.into(), self.start_time.to_string().into(), self.end_time.to_string().into(),
+             self.direction.to_string().into(), self.exit_rule.to_string().into(), self.sharpe.into(),
+             self.psr.into(), self.deflated_sharpe.into(), self.p_value.into(), self.adjusted_p_value.into(),
+             self.n_obs.into()]
+    }
+}
+
+/// Sweep-wide overfitting and data snooping statistics
+pub struct SignificanceSummary
+{
+    pub trials: usize,
+    /// Cells given the joint tests
+    pub joint_trials: usize,
+    /// Days the jointly tested cells traded on
+    pub days: usize,
+    /// Per trade Sharpe ratio the best of `trials` unskilled cells is expected to reach
+    pub expected_max_sharpe: f64,
+    /// Probability of backtest overfitting from combinatorially symmetric cross-validation
+    pub pbo: f64,
+    /// White's Reality Check p-value for the best cell beating zero
+    pub reality_check_p: f64,
+    /// Hansen's consistent SPA test p-value
+    pub spa_p: f64,
+    /// Key columns of the cell with the highest deflated Sharpe ratio
+    pub best: String,
+}
+
+pub static SIGNIFICANCE_SUMMARY_FIELD_NAMES: [&str; 8] = ["trials", "joint trials", "days", "expected max sharpe",
+                                                          "pbo", "reality check p", "spa p", "best cell"];
+
+pub struct SignificanceReport
+{
+    pub cells: Vec<CellSignificance>,
+    pub summary: SignificanceSummary,
+}
+
+impl FieldsToStrings for SignificanceSummary
+{
+    fn fields_to_strings(&self) -> Vec<String>
+    {
+        vec![self.trials.to_string(), self.joint_trials.to_string(), self.days.to_string(),
+             self.expected_max_sharpe.to_string(),
+             self.pbo.to_string(), self.reality_check_p.to_string(), self.spa_p.to_string(), self.best.clone()]
+    }
+}
+
+impl FieldsToValues for SignificanceSummary
+{
+    fn field_types(_: usize) -> Vec<FieldType>
+    {
+        use FieldType::*;
+        vec![Int, Int, Int, Float, Float, Float, Float, Text]
+    }
+
+    fn fields_to_values(&self) -> Vec<FieldValue>
+    {
+        vec![self.trials.into(), self.joint_trials.into(), self.days.into(), self.expected_max_sharpe.into(),
+             self.pbo.into(), self.reality_check_p.into(), self.spa_p.into(), self.best.clone().into()]
+    }
+}
+
+/// Recomputes every result's trades for its own statistics, keeping only their moments, then tests
+/// the `top_n` cells by trade Sharpe ratio jointly. Their daily returns are aligned on the days any
+/// of them traded, zero on days a cell didn't.
+pub fn analyse(engine: &WindowEngine, results: &[StrategyResult], spec: &StrategySpec,
+               config: &SignificanceConfig) -> Result<SignificanceReport, Box<dyn Error>>
+{
+    if results.is_empty() {
+        return Err(Box::new(SimpleError::new("No results to test for significance")))
+    }
+    let net = config.net && spec.costs.is_some();
+    let returns = |r: &StrategyResult| -> Vec<(NaiveDate, f64)> {
+        run_trades(engine, r.interval, &r.start_time, r.direction, &r.exit_rule, spec).iter()
+            .map(|t| (t.entry_datetime.date(), if net { t.net_ret } else { t.ret }))
+            .collect()
+    };
+    let moments: Vec<(f64, f64, f64, usize)> = results.par_iter()
+        .map(|r| trade_moments(&returns(r).into_iter().map(|x| x.1).collect::<Vec<f64>>()))
+        .collect();
+    let (cell_stats, expected_max_sharpe) = deflated_sharpe(&moments);
+    let p_values: Vec<f64> = cell_stats.iter().map(|x| 1. - x.1).collect();
+    let adjusted = benjamini_hochberg(&p_values);
+
+    let mut joint: Vec<usize> = (0..results.len()).filter(|&i| moments[i].0.is_finite()).collect();
+    joint.sort_by(|&a, &b| moments[b].0.total_cmp(&moments[a].0));
+    if config.top_n > 0 {
+        joint.truncate(config.top_n);
+    }
+    let traded: Vec<Vec<(NaiveDate, f64)>> = joint.par_iter().map(|&i| returns(&results[i])).collect();
+    let mut days: Vec<NaiveDate> = traded.iter().flatten().map(|x| x.0).collect();
+    days.sort();
+    days.dedup();
+    let daily: Vec<Vec<f64>> = traded.into_iter()
+        .map(|trades| {
+            let mut daily = vec![0.; days.len()];
+            for (day, ret) in trades.iter() {
+                daily[days.binary_search(day).unwrap()] += ret;
+            }
+            daily
+        })
+        .collect();
+    info!("Testing {} cells, {} of them jointly over {} days", results.len(), daily.len(), days.len());
+    let daily: Vec<&[f64]> = daily.iter().map(|x| x.as_slice()).collect();
+    let (pbo, reality_check_p, spa_p) = if daily.is_empty() || days.is_empty() {
+        warn!("No cell has a finite Sharpe ratio to test jointly");
+        (f64::NAN, f64::NAN, f64::NAN)
+    } else {
+        let pbo = if days.len() >= config.partitions {
+            probability_of_overfitting(&daily, config.partitions)
+        } else {
+            warn!("{} days are too few for {} CSCV partitions", days.len(), config.partitions);
+            f64::NAN
+        };
+        let (reality_check_p, spa_p) = reality_check(&daily, config);
+        (pbo, reality_check_p, spa_p)
+    };
+
+    let cells: Vec<CellSignificance> = results.iter().zip(cell_stats.iter()).zip(adjusted.iter())
+        .map(|((r, &(sharpe, psr, deflated_sharpe, n_obs)), &adjusted_p_value)| CellSignificance {
+            interval: r.interval,
+            start_time: r.start_time,
+            end_time: r.end_time,
+            direction: r.direction,
+            exit_rule: r.exit_rule,
+            sharpe,
+            psr,
+            deflated_sharpe,
+            p_value: 1. - psr,
+            adjusted_p_value,
+            n_obs,
+        })
+        .collect();
+    let best = match cells.iter()
+        .filter(|x| !x.deflated_sharpe.is_nan())
+        .max_by(|a, b| a.deflated_sharpe.total_cmp(&b.deflated_sharpe))
+    {
+        Some(x) => format!("{} {} {} {}", x.interval, x.start_time, x.direction, x.exit_rule),
+        None => {
+            warn!("No cell has a deflated Sharpe ratio to report as the best");
+            String::new()
+        },
+    };
+    let summary = SignificanceSummary { trials: cells.len(), joint_trials: daily.len(), days: days.len(),
+                                        expected_max_sharpe, pbo, reality_check_p, spa_p, best };
+    Ok(SignificanceReport { cells, summary })
+}
+
+/// Per trade Sharpe ratio, skew, kurtosis and count of a cell's trade returns
+fn trade_moments(trades: &[f64]) -> (f64, f64, f64, usize)
+{
+    let n = trades.len() as f64;
+    let mean = trades.iter().sum::<f64>() / n;
+    let var = trades.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.);
+    (mean / var.sqrt(), moment(trades, 3), moment(trades, 4), trades.len())
+}
+
+/// Per cell (sharpe, psr, deflated sharpe, n obs) from each cell's trade moments, and the expected
+/// maximum Sharpe ratio of the trials under the null, after Bailey and Lopez de Prado
+fn deflated_sharpe(moments: &[(f64, f64, f64, usize)]) -> (Vec<(f64, f64, f64, usize)>, f64)
+{
+    let sharpes: Vec<f64> = moments.iter().map(|x| x.0).filter(|x| x.is_finite()).collect();
+    let trials = sharpes.len() as f64;
+    let expected_max_sharpe = if trials < 2. { 0. } else {
+        let mean = sharpes.iter().sum::<f64>() / trials;
+        let var = sharpes.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (trials - 1.);
+        var.sqrt() * ((1. - EULER_MASCHERONI) * inverse_normal_cdf(1. - 1. / trials)
+            + EULER_MASCHERONI * inverse_normal_cdf(1. - 1. / (trials * std::f64::consts::E)))
+    };
+    let psr = |sr: f64, skew: f64, kurt: f64, n: usize, benchmark: f64| {
+        let denom = (1. - skew * sr + (kurt - 1.) / 4. * sr * sr).sqrt();
+        normal_cdf((sr - benchmark) * ((n as f64) - 1.).sqrt() / denom)
+    };
+    let stats = moments.iter()
+        .map(|&(sr, skew, kurt, n)| (sr, psr(sr, skew, kurt, n, 0.), psr(sr, skew, kurt, n, expected_max_sharpe), n))
+        .collect();
+    (stats, expected_max_sharpe)
+}
+
+/// Benjamini-Hochberg adjusted p-values, in the order given. NaN p-values stay NaN and don't count
+/// as tests.
+pub fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64>
+{
+    let mut order: Vec<usize> = (0..p_values.len()).filter(|&i| !p_values[i].is_nan()).collect();
+    order.sort_by(|&a, &b| p_values[a].total_cmp(&p_values[b]));
+    let m = order.len() as f64;
+    let mut adjusted = vec![f64::NAN; p_values.len()];
+    let mut running_min = 1_f64;
+    for (rank, &i) in order.iter().enumerate().rev() {
+        running_min = running_min.min(p_values[i] * m / (rank + 1) as f64);
+        adjusted[i] = running_min;
+    }
+    adjusted
+}
+
+/// Probability of backtest overfitting by combinatorially symmetric cross-validation: the share of
+/// in-sample/out-of-sample splits of `partitions` day blocks where the best in-sample cell ranks in
+/// the bottom half out of sample
+pub fn probability_of_overfitting(daily: &[&[f64]], partitions: usize) -> f64
+{
+    let t = daily[0].len();
+    // (sum, sum of squares, count) of each cell over each block
+    let blocks: Vec<Vec<(f64, f64, f64)>> = daily.iter()
+        .map(|row| (0..partitions)
+            .map(|s| {
+                let block = &row[s * t / partitions..(s + 1) * t / partitions];
+                (block.iter().sum(), block.iter().map(|x| x * x).sum(), block.len() as f64)
+            })
+            .collect())
+        .collect();
+    let sharpe = |row: &[(f64, f64, f64)], selected: &[bool], keep: bool| {
+        let (sum, sum_sq, n) = row.iter().zip(selected.iter())
+            .filter(|(_, &s)| s == keep)
+            .fold((0., 0., 0.), |acc, (x, _)| (acc.0 + x.0, acc.1 + x.1, acc.2 + x.2));
+        let sr = sum / n / ((sum_sq - sum * sum / n) / (n - 1.)).sqrt();
+        if sr.is_finite() { sr } else { f64::NEG_INFINITY }
+    };
+    let splits: Vec<Vec<usize>> = (0..partitions).combinations(partitions / 2).collect();
+    let logits: Vec<f64> = splits.par_iter()
+        .map(|in_sample| {
+            let selected: Vec<bool> = (0..partitions).map(|s| in_sample.contains(&s)).collect();
+            let best = (0..blocks.len())
+                .max_by(|&a, &b| sharpe(&blocks[a], &selected, true).total_cmp(&sharpe(&blocks[b], &selected, true)))
+                .unwrap();
+            let oos: Vec<f64> = blocks.iter().map(|row| sharpe(row, &selected, false)).collect();
+            let below = oos.iter().filter(|&&x| x < oos[best]).count() as f64;
+            let ties = oos.iter().filter(|&&x| x == oos[best]).count() as f64;
+            let omega = (below + ties / 2.) / (blocks.len() as f64 + 1.);
+            (omega / (1. - omega)).ln()
+        })
+        .collect();
+    logits.iter().filter(|&&x| x <= 0.).count() as f64 / logits.len() as f64
+}
+
+/// White's Reality Check and Hansen's consistent SPA p-values for the best mean daily return
+/// across cells beating zero, from a seeded stationary bootstrap
+pub fn reality_check(daily: &[&[f64]], config: &SignificanceConfig) -> (f64, f64)
+{
+    let t = daily[0].len();
+    let samples = stationary_bootstrap(t, config.bootstrap_samples, config.block_length, config.seed);
+    let sqrt_t = (t as f64).sqrt();
+    let threshold = -(2. * (t as f64).ln().max(1.).ln()).sqrt();
+    let identity = || (f64::NEG_INFINITY, 0_f64, vec![f64::NEG_INFINITY; samples.len()], vec![0_f64; samples.len()]);
+    let (rc_stat, spa_stat, rc_boot, spa_boot) = daily.par_iter()
+        .map(|row| {
+            let mean = row.iter().sum::<f64>() / t as f64;
+            let boot: Vec<f64> = samples.iter().map(|ix| ix.iter().map(|&i| row[i as usize]).sum::<f64>() / t as f64).collect();
+            let boot_mean = boot.iter().sum::<f64>() / boot.len() as f64;
+            let omega = sqrt_t * (boot.iter().map(|x| (x - boot_mean).powi(2)).sum::<f64>() / boot.len() as f64).sqrt();
+            let rc_boot: Vec<f64> = boot.iter().map(|x| sqrt_t * (x - mean)).collect();
+            if omega <= 0. {
+                return (sqrt_t * mean, 0., rc_boot, vec![0.; boot.len()])
+            }
+            let studentized = sqrt_t * mean / omega;
+            let recentre = if studentized >= threshold { mean } else { 0. };
+            let spa_boot = boot.iter().map(|x| (sqrt_t * (x - recentre) / omega).max(0.)).collect();
+            (sqrt_t * mean, studentized.max(0.), rc_boot, spa_boot)
+        })
+        .reduce(identity, |a, b| (
+            a.0.max(b.0),
+            a.1.max(b.1),
+            a.2.iter().zip(b.2.iter()).map(|(x, y)| x.max(*y)).collect(),
+            a.3.iter().zip(b.3.iter()).map(|(x, y)| x.max(*y)).collect(),
+        ));
+    let p = |stat: f64, boot: &[f64]| boot.iter().filter(|&&x| x >= stat).count() as f64 / boot.len() as f64;
+    (p(rc_stat, &rc_boot), p(spa_stat, &spa_boot))
+}
+
+/// Standard normal CDF, using the Abramowitz and Stegun 7.1.26 approximation of erf
+pub fn normal_cdf(x: f64) -> f64
+{
+    let z = x.abs() / std::f64::consts::SQRT_2;
+    let k = 1. / (1. + 0.3275911 * z);
+    let poly = k * (0.254829592 + k * (-0.284496736 + k * (1.421413741 + k * (-1.453152027 + k * 1.061405429))));
+    let erf = 1. - poly * (-z * z).exp();
+    if x >= 0. { (1. + erf) / 2. } else { (1. - erf) / 2. }
+}
+
+/// Standard normal quantile, by Acklam's rational approximation
+pub fn inverse_normal_cdf(p: f64) -> f64
+{
+    const A: [f64; 6] = [-3.969683028665376e+01, 2.209460984245205e+02, -2.759285104469687e+02,
+                         1.38357751867269e+02, -3.066479806614716e+01, 2.506628277459239e+00];
+    const B: [f64; 5] = [-5.447609879822406e+01, 1.615858368580409e+02, -1.556989798598866e+02,
+                         6.680131188771972e+01, -1.328068155288572e+01];
+    const C: [f64; 6] = [-7.784894002430293e-03, -3.223964580411365e-01, -2.400758277161838e+00,
+                         -2.549732539343734e+00, 4.374664141464968e+00, 2.938163982698783e+00];
+    const D: [f64; 4] = [7.784695709041462e-03, 3.224671290700398e-01, 2.445134137142996e+00,
+                         3.754408661907416e+00];
+    const P_LOW: f64 = 0.02425;
+    if p <= 0. { return f64::NEG_INFINITY }
+    if p >= 1. { return f64::INFINITY }
+    let tail = |q: f64| (((((C[0]*q + C[1])*q + C[2])*q + C[3])*q + C[4])*q + C[5])
+        / ((((D[0]*q + D[1])*q + D[2])*q + D[3])*q + 1.);
+    if p < P_LOW {
+        tail((-2. * p.ln()).sqrt())
+    } else if p > 1. - P_LOW {
+        -tail((-2. * (1. - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0]*r + A[1])*r + A[2])*r + A[3])*r + A[4])*r + A[5])*q
+            / (((((B[0]*r + B[1])*r + B[2])*r + B[3])*r + B[4])*r + 1.)
+    }
+}
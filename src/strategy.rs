@@ -1,20 +1,188 @@
-use crate::BUS_DAY_CAL;
-use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
-use bdays::HolidayCalendar;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::str::FromStr;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use serde_derive::Deserialize;
+use crate::window::{CellTime, WindowEngine};
+use crate::bars::BarSeries;
+use crate::costs::CostModel;
+use crate::metrics::{MetricSet, Observations};
+use crate::bootstrap::Confidence;
+use crate::contracts::{ContractSpec, Units};
+use crate::calendars::TradingCalendar;
+use crate::exits::{ExitReason, ExitRule};
 
-pub const N_FIELDS: usize = 7;
-pub static FIELD_NAMES: [&str; N_FIELDS] = ["interval", "start time", "end time", "sharpe",
-                                            "max drawup", "max drawdown", "n obs"];
+/// Side a strategy takes at entry. `Drift` follows the sign of the price change over the given
+/// number of bars before entry and `Fade` takes the opposite side; both stay flat when that change
+/// is zero or the lookback bar isn't on the entry day.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Long,
+    Short,
+    Drift(usize),
+    Fade(usize),
+}
+
+impl Direction
+{
+    /// +1 for long, -1 for short, `None` when the strategy doesn't trade this window. Drift is
+    /// measured between fill prices so it never looks past the entry.
+    pub fn side(&self, bars: &BarSeries, entry_ix: usize, fill: Fill) -> Option<f64>
+    {
+        let drift_sign = |lookback: usize| {
+            let ix = entry_ix.checked_sub(lookback)?;
+            if bars.date(ix) != bars.date(entry_ix) { return None }
+            let drift = fill.price(bars, entry_ix) - fill.price(bars, ix);
+            if drift == 0. { None } else { Some(drift.signum()) }
+        };
+        match self {
+            Direction::Long => Some(1.),
+            Direction::Short => Some(-1.),
+            Direction::Drift(lookback) => drift_sign(*lookback),
+            Direction::Fade(lookback) => drift_sign(*lookback).map(|x| -x),
+        }
+    }
+}
+
+/// Price trades fill at on their entry and scheduled exit bars
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Fill {
+    #[default]
+    Close,
+    Open,
+}
+
+impl Fill
+{
+    pub fn price(&self, bars: &BarSeries, i: usize) -> f64
+    {
+        match self {
+            Fill::Close => bars.close()[i],
+            Fill::Open => bars.open()[i],
+        }
+    }
+
+    /// First and last bars a trade filled on `entry_ix` and `exit_ix` is exposed to
+    pub fn held_bars(&self, entry_ix: usize, exit_ix: usize) -> (usize, usize)
+    {
+        match self {
+            Fill::Close => (entry_ix + 1, exit_ix),
+            Fill::Open => (entry_ix, exit_ix - 1),
+        }
+    }
+}
+
+impl fmt::Display for Direction
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Direction::Long => write!(f, "long"),
+            Direction::Short => write!(f, "short"),
+            Direction::Drift(n) => write!(f, "drift:{}", n),
+            Direction::Fade(n) => write!(f, "fade:{}", n),
+        }
+    }
+}
+
+impl FromStr for Direction
+{
+    type Err = Box<dyn Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s.split_once(':') {
+            None if s == "long" => Ok(Direction::Long),
+            None if s == "short" => Ok(Direction::Short),
+            Some(("drift", n)) => Ok(Direction::Drift(n.parse()?)),
+            Some(("fade", n)) => Ok(Direction::Fade(n.parse()?)),
+            _ => Err(format!("Invalid direction '{}', expected long, short, drift:N or fade:N", s).into()),
+        }
+    }
+}
+
+/// How every window of a sweep is traded and scored
+pub struct StrategySpec {
+    pub directions: Vec<Direction>,
+    pub fill: Fill,
+    /// Intraday exits tried for every cell, `ExitRule::default()` holding to the window's end
+    pub exit_rules: Vec<ExitRule>,
+    /// When set, each metric is also reported net of costs after the gross columns
+    pub costs: Option<CostModel>,
+    pub metrics: MetricSet,
+    /// Bootstrap intervals reported after the metrics when set
+    pub confidence: Option<Confidence>,
+    /// Traded contract, whose session close ends every window
+    pub contract: ContractSpec,
+    pub units: Units,
+    /// Exchange calendar, whose half days end windows at their early close
+    pub calendar: TradingCalendar,
+}
+
+impl StrategySpec
+{
+    /// Round trip cost in price points of a trade between two bars, zero without a cost model
+    pub fn round_trip_cost(&self, engine: &WindowEngine, entry_ix: usize, exit_ix: usize) -> f64
+    {
+        match &self.costs {
+            Some(c) => c.round_trip(engine.bars.spread(entry_ix), engine.bars.spread(exit_ix)),
+            None => 0.,
+        }
+    }
+
+    /// Multiplier from price points to the reporting units
+    pub fn unit_scale(&self) -> f64
+    {
+        self.contract.unit_scale(self.units)
+    }
+
+    /// Metric values for a cell, followed by their net of cost values when costs are modelled
+    pub fn compute_metrics(&self, obs: &Observations, net_obs: &Observations) -> Vec<f64>
+    {
+        let mut metrics = self.metrics.compute(obs);
+        if self.costs.is_some()
+        {
+            metrics.extend(self.metrics.compute(net_obs));
+        }
+        metrics
+    }
+
+    /// Bootstrap interval bounds for a cell, empty unless confidence intervals are configured
+    pub fn confidence_intervals<K: Hash>(&self, obs: &Observations, net_obs: &Observations, cell: K) -> Vec<f64>
+    {
+        match &self.confidence {
+            Some(c) if c.config.net && self.costs.is_some() => c.intervals(net_obs, cell),
+            Some(c) => c.intervals(obs, cell),
+            None => Vec::new(),
+        }
+    }
+
+    /// CSV header for the `StrategyResult` rows this spec produces
+    pub fn field_names(&self) -> Vec<String>
+    {
+        let mut v = self.metrics.field_names(self.costs.is_some());
+        if let Some(c) = &self.confidence {
+            let n_obs = v.pop().unwrap();
+            v.extend(c.field_names());
+            v.push(n_obs);
+        }
+        v
+    }
+}
+
+/// Metrics for one interval/start time cell, in the order of the `MetricSet` that produced them,
+/// followed by their net of cost values when costs are modelled and then any confidence bounds.
+/// The CSV header comes from `StrategySpec::field_names`.
 pub struct StrategyResult {
     pub interval: u64,
-    pub start_time: NaiveTime,
-    pub end_time: NaiveTime,
-    pub sharpe: f64,
-    pub max_drawup: f64,
-    pub max_drawdown: f64,
+    pub start_time: CellTime,
+    pub end_time: CellTime,
+    pub direction: Direction,
+    pub exit_rule: ExitRule,
+    pub metrics: Vec<f64>,
     pub n_obs: usize,
-    // pub datetime_data: Vec<Vec<NaiveDateTime>>,
-    // pub value_data: Vec<Vec<f64>>,
 }
 
 pub trait FieldsToStrings
@@ -22,14 +190,98 @@ pub trait FieldsToStrings
     fn fields_to_strings(&self) -> Vec<String>;
 }
 
+/// Type of an output column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType
+{
+    Int,
+    Float,
+    Date,
+    DateTime,
+    Text,
+}
+
+/// One field of an output row, as written to typed columnar files
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue
+{
+    Int(i64),
+    Float(f64),
+    Date(NaiveDate),
+    DateTime(NaiveDateTime),
+    Text(String),
+}
+
+impl FieldValue
+{
+    pub fn field_type(&self) -> FieldType
+    {
+        match self {
+            FieldValue::Int(_) => FieldType::Int,
+            FieldValue::Float(_) => FieldType::Float,
+            FieldValue::Date(_) => FieldType::Date,
+            FieldValue::DateTime(_) => FieldType::DateTime,
+            FieldValue::Text(_) => FieldType::Text,
+        }
+    }
+}
+
+impl From<u64> for FieldValue { fn from(x: u64) -> Self { FieldValue::Int(x as i64) } }
+impl From<usize> for FieldValue { fn from(x: usize) -> Self { FieldValue::Int(x as i64) } }
+impl From<f64> for FieldValue { fn from(x: f64) -> Self { FieldValue::Float(x) } }
+impl From<NaiveDate> for FieldValue { fn from(x: NaiveDate) -> Self { FieldValue::Date(x) } }
+impl From<NaiveDateTime> for FieldValue { fn from(x: NaiveDateTime) -> Self { FieldValue::DateTime(x) } }
+impl From<String> for FieldValue { fn from(x: String) -> Self { FieldValue::Text(x) } }
+
+/// Output rows with a fixed column type per field, so every file written from one kind of row has
+/// the same schema whatever its values
+pub trait FieldsToValues
+{
+    /// Types of the columns of rows with `n` fields, as some rows carry a configured number of metrics
+    fn field_types(n: usize) -> Vec<FieldType>;
+    fn fields_to_values(&self) -> Vec<FieldValue>;
+}
+
+/// Column types of the interval, start time, end time, direction and exit rule keys of a cell
+pub static CELL_KEY_TYPES: [FieldType; 5] = [FieldType::Int, FieldType::Text, FieldType::Text, FieldType::Text,
+                                              FieldType::Text];
+
+/// `leading` then floats up to a final integer count column, for rows of `n` fields ending in metrics and n obs
+pub fn metric_field_types(leading: &[FieldType], n: usize) -> Vec<FieldType>
+{
+    let mut v = leading.to_vec();
+    v.resize(n.saturating_sub(1).max(leading.len()), FieldType::Float);
+    v.push(FieldType::Int);
+    v
+}
+
 impl FieldsToStrings for StrategyResult
 {
     fn fields_to_strings(&self) -> Vec<String>
     {
-        // Doesn't include datetime or value data yet
-        vec![self.interval.to_string(), self.start_time.to_string(), self.end_time.to_string(),
-            self.sharpe.to_string(), self.max_drawup.to_string(), self.max_drawdown.to_string(),
-            self.n_obs.to_string()]
+        let mut v = vec![self.interval.to_string(), self.start_time.to_string(), self.end_time.to_string(),
+                         self.direction.to_string(), self.exit_rule.to_string()];
+        v.extend(self.metrics.iter().map(|x| x.to_string()));
+        v.push(self.n_obs.to_string());
+        v
+    }
+}
+
+impl FieldsToValues for StrategyResult
+{
+    fn field_types(n: usize) -> Vec<FieldType>
+    {
+        metric_field_types(&CELL_KEY_TYPES, n)
+    }
+
+    fn fields_to_values(&self) -> Vec<FieldValue>
+    {
+        let mut v: Vec<FieldValue> = vec![self.interval.into(), self.start_time.to_string().into(),
+                                          self.end_time.to_string().into(), self.direction.to_string().into(),
+                                          self.exit_rule.to_string().into()];
+        v.extend(self.metrics.iter().map(|&x| x.into()));
+        v.push(self.n_obs.into());
+        v
     }
 }
 impl Default for StrategyResult
@@ -39,77 +291,62 @@ impl Default for StrategyResult
         Self
         {
             interval: 0,
-            start_time: NaiveTime::from_hms(1,0,0),
-            end_time: NaiveTime::from_hms(1,0,0),
-            sharpe: f64::NAN,
-            max_drawup: f64::NAN,
-            max_drawdown: f64::NAN,
+            start_time: CellTime::Clock(NaiveTime::from_hms_opt(1,0,0).unwrap()),
+            end_time: CellTime::Clock(NaiveTime::from_hms_opt(1,0,0).unwrap()),
+            direction: Direction::Long,
+            exit_rule: ExitRule::default(),
+            metrics: Vec::new(),
             n_obs: 0,
-            // datetime_data: Vec::new(),
-            // value_data: Vec::new(),
         }
     }
 }
 
-pub trait ContextCondition {}
+pub const N_TRADE_FIELDS: usize = 12;
+pub static TRADE_FIELD_NAMES: [&str; N_TRADE_FIELDS] = ["entry datetime", "exit datetime", "side", "entry price",
+                                                        "exit price", "exit reason", "return", "cost",
+                                                        "net return", "mfe", "mae", "bars held"];
+/// A single entry/exit window behind a `StrategyResult`. Return and excursions are signed by the
+/// side taken and measured from the entry price over the highs and lows of the bars held. Returns,
+/// costs and excursions are in the spec's units.
+pub struct Trade {
+    pub entry_datetime: NaiveDateTime,
+    pub exit_datetime: NaiveDateTime,
+    pub side: f64,
+    pub entry_price: f64,
+    pub exit_price: f64,
+    pub exit_reason: ExitReason,
+    pub ret: f64,
+    pub cost: f64,
+    pub net_ret: f64,
+    pub mfe: f64,
+    pub mae: f64,
+    pub bars_held: usize,
+}
 
-pub struct DayOfCondition;
-impl DayOfCondition
+impl FieldsToStrings for Trade
 {
-    pub fn run(datetimes: &Vec<NaiveDateTime>, event_dates: &Vec<NaiveDate>) -> Vec<bool>
+    fn fields_to_strings(&self) -> Vec<String>
     {
-        datetimes.iter()
-            .map(|x| event_dates.contains(&x.date()))
-            .collect()
+        vec![self.entry_datetime.to_string(), self.exit_datetime.to_string(), self.side.to_string(),
+            self.entry_price.to_string(),
+            self.exit_price.to_string(), self.exit_reason.to_string(), self.ret.to_string(), self.cost.to_string(), self.net_ret.to_string(),
+            self.mfe.to_string(), self.mae.to_string(),
+            self.bars_held.to_string()]
     }
 }
-impl ContextCondition for DayOfCondition {}
 
-pub struct DayOffsetCondition;
-impl DayOffsetCondition
+impl FieldsToValues for Trade
 {
-    pub fn run(datetimes: &Vec<NaiveDateTime>, event_dates: &Vec<NaiveDate>,
-               early_offset_days: i64, late_offset_days: i64, is_bus_days: bool) -> Vec<bool> {
-        assert!(early_offset_days<=late_offset_days);
-        let mut event_date_ranges = event_dates.clone();
-        for &dt in event_dates {
-            for i in early_offset_days..=late_offset_days {
-                if is_bus_days {
-                    event_date_ranges.push(BUS_DAY_CAL.advance_bdays(dt, -i as i32))
-                }
-                else {
-                    event_date_ranges.push(dt + Duration::days(-i));
-                }
-            }
-        }
-        datetimes.iter().map(|x| event_date_ranges.contains(&x.date())).collect()
+    fn field_types(_: usize) -> Vec<FieldType>
+    {
+        use FieldType::*;
+        vec![DateTime, DateTime, Float, Float, Float, Text, Float, Float, Float, Float, Float, Int]
     }
-}
-impl ContextCondition for DayOffsetCondition {}
-
-pub fn day_of_strat(datetimes: &Vec<NaiveDateTime>, event_dates: &Vec<NaiveDate>) -> Vec<bool>
-{
-    datetimes.iter()
-        .map(|x| event_dates.contains(&x.date()))
-        .collect()
-}
 
-pub fn days_offset_strat(datetimes: &Vec<NaiveDateTime>, event_dates: &Vec<NaiveDate>,
-                         early_offset_days: i64, late_offset_days: i64, is_bus_days: bool) -> Vec<bool>
-{
-    assert!(early_offset_days<=late_offset_days);
-    let mut event_date_ranges = event_dates.clone();
-    for &dt in event_dates {
-        for i in early_offset_days..=late_offset_days {
-            if is_bus_days {
-                event_date_ranges.push(BUS_DAY_CAL.advance_bdays(dt, -i as i32))
-            }
-            else {
-                event_date_ranges.push(dt + Duration::days(-i));
-            }
-        }
+    fn fields_to_values(&self) -> Vec<FieldValue>
+    {
+        vec![self.entry_datetime.into(), self.exit_datetime.into(), self.side.into(), self.entry_price.into(),
+             self.exit_price.into(), self.exit_reason.to_string().into(), self.ret.into(), self.cost.into(),
+             self.net_ret.into(), self.mfe.into(), self.mae.into(), self.bars_held.into()]
     }
-    datetimes.iter().map(|x| event_date_ranges.contains(&x.date())).collect()
 }
-
-
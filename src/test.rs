@@ -1,5 +1,763 @@
+use chrono::NaiveTime;
+use crate::config::{ConditionConfig, RunConfig};
+
 #[test]
 fn general_test() {
 
 
-}
\ No newline at end of file
+}
+
+#[test]
+fn run_config_test() {
+    let yaml = "
+data: { path: bars.csv }
+events: { path: events.csv }
+output: { dir: out }
+sweep:
+  resolution: 5
+  interval: { start: 2, end: 20 }
+  start_time: { start: \"06:00\", end: \"06:30:00\" }
+";
+    let config: RunConfig = serde_yaml::from_str(yaml).unwrap();
+    assert_eq!(config.sweep.interval_rng(), vec![5, 10, 15, 20]);
+    assert_eq!(config.sweep.start_time.as_ref().unwrap().end, NaiveTime::from_hms_opt(6, 30, 0).unwrap());
+    assert_eq!(config.threads, 12);
+    assert_eq!(config.data.cache_dir.as_deref(), Some("cache"));
+    assert!(matches!(config.conditions[..], [ConditionConfig::DayOf { event: None, .. }]));
+    // Paths don't exist, so validation must fail rather than panic later on
+    assert!(config.validate().is_err());
+
+    // An interval range with no multiple of the resolution leaves an empty grid
+    let empty: RunConfig = serde_yaml::from_str(&yaml.replace("{ start: 2, end: 20 }", "{ start: 2, end: 4 }")).unwrap();
+    assert!(empty.sweep.interval_rng().is_empty());
+    let e = empty.validate().unwrap_err().to_string();
+    assert!(e.contains("sweep.interval 2..=4 has no multiple of resolution 5"), "{}", e);
+    assert!(!config.validate().unwrap_err().to_string().contains("no multiple"));
+}
+
+#[test]
+fn cli_test() {
+    use clap::Parser;
+    use crate::cli::{Cli, Command};
+    use crate::exits::StopDistance;
+    use crate::strategy::Direction;
+    let parse = |args: &str| Cli::try_parse_from(args.split_whitespace());
+
+    let cli = parse("backtesting -c run.yaml --data bars.csv --no-cache sweep -e NFP -e CPI -t 1").unwrap();
+    assert_eq!((cli.config.as_str(), cli.data.as_deref(), cli.no_cache), ("run.yaml", Some("bars.csv"), true));
+    assert!(matches!(cli.command, Command::Sweep { event, threads: Some(1) } if event == ["NFP", "CPI"]));
+    let cli = parse("backtesting walk-forward -m sharpe -m sortino").unwrap();
+    assert_eq!((cli.config.as_str(), cli.metric.len()), ("config/run.yaml", 2));
+    assert!(matches!(cli.command, Command::WalkForward { threads: None, .. }));
+    assert!(matches!(parse("backtesting surprise -e NFP --events-file e.csv").unwrap(),
+                     Cli { command: Command::Surprise { .. }, events_file: Some(_), .. }));
+    assert!(matches!(parse("backtesting events -o out").unwrap().command, Command::Events));
+    assert!(matches!(parse("backtesting inspect").unwrap().command, Command::Inspect));
+
+    let cli = parse("backtesting run -e NFP -i 30 --offset -15 -d drift:10 --stop-loss 8t --time-stop 20").unwrap();
+    match cli.command {
+        Command::Run { event, interval, start_time, offset, direction, stop_loss, time_stop, .. } => {
+            assert_eq!((event.as_str(), interval, start_time, offset), ("NFP", 30, None, Some(-15)));
+            assert_eq!((direction, stop_loss, time_stop), (Direction::Drift(10), Some(StopDistance::Ticks(8.)), Some(20)));
+        },
+        _ => panic!("expected run"),
+    }
+    assert!(parse("backtesting run -e NFP -i 30 -s 08:30").is_ok());
+    // run needs an entry time or offset, and not both
+    assert!(parse("backtesting run -e NFP -i 30").is_err());
+    assert!(parse("backtesting run -e NFP -i 30 -s 08:30 --offset 5").is_err());
+    assert!(parse("backtesting run -e NFP -i 30 -s 8h").is_err());
+    assert!(parse("backtesting").is_err());
+}
+
+#[test]
+fn run_trades_test() {
+    use chrono::NaiveDateTime;
+    use crate::analysis::run_trades;
+    use crate::strategy::{Direction, Fill, StrategySpec};
+    use crate::costs::CostModel;
+    use crate::contracts::{ContractRegistry, Units};
+    use crate::exits::ExitRule;
+    use crate::metrics::MetricSet;
+    use crate::bars::BarSeries;
+    use crate::window::{CellTime, WindowEngine};
+    use crate::conditions::Always;
+    use crate::calendars::TradingCalendar;
+    let start = NaiveDateTime::parse_from_str("2021-01-05 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+    let datetimes: Vec<NaiveDateTime> = (0..10).map(|i| start + chrono::Duration::minutes(i)).collect();
+    let values = vec![1.0, 2.0, 3.0, 1.5, 0.5, 2.5, 4.0, 3.0, 3.0, 3.0];
+
+    let engine = WindowEngine::new(BarSeries::from_closes(datetimes, values), &Always);
+    let costs = CostModel { slippage_ticks: 1., tick_size: Some(0.25), ..Default::default() };
+    let contract = ContractRegistry::default().get("ZN").unwrap().clone();
+    let mut spec = StrategySpec { directions: vec![Direction::Long], fill: Fill::Close, exit_rules: vec![ExitRule::default()],
+                                  costs: Some(costs), metrics: MetricSet::default(), confidence: None, contract,
+                                  units: Units::Points, calendar: TradingCalendar::default() };
+    let trades = run_trades(&engine, 4, &CellTime::Clock(NaiveTime::from_hms_opt(8, 1, 0).unwrap()), Direction::Long, &ExitRule::default(), &spec);
+    assert_eq!(trades.len(), 1);
+    let t = &trades[0];
+    assert_eq!((t.entry_price, t.exit_price, t.ret, t.bars_held), (2.0, 2.5, 0.5, 4));
+    assert_eq!((t.mfe, t.mae), (1.0, -1.5));
+    assert_eq!((t.cost, t.net_ret), (0.5, 0.0));
+
+    // Drift over the 1 bar before 08:01 is up, so follows long; fade goes short
+    assert_eq!(run_trades(&engine, 4, &CellTime::Clock(NaiveTime::from_hms_opt(8, 1, 0).unwrap()), Direction::Drift(1), &ExitRule::default(), &spec)[0].side, 1.);
+    let t = &run_trades(&engine, 4, &CellTime::Clock(NaiveTime::from_hms_opt(8, 1, 0).unwrap()), Direction::Fade(1), &ExitRule::default(), &spec)[0];
+    assert_eq!((t.side, t.ret, t.mfe, t.mae), (-1., -0.5, 1.5, -1.0));
+    // No bar before the first one to measure drift from
+    assert!(run_trades(&engine, 4, &CellTime::Clock(NaiveTime::from_hms_opt(8, 0, 0).unwrap()), Direction::Drift(1), &ExitRule::default(), &spec).is_empty());
+    // A half day closing at 08:05 cuts the window off
+    spec.calendar.half_days.insert(start.date(), NaiveTime::from_hms_opt(8, 5, 0).unwrap());
+    assert!(run_trades(&engine, 4, &CellTime::Clock(NaiveTime::from_hms_opt(8, 1, 0).unwrap()), Direction::Long, &ExitRule::default(), &spec).is_empty());
+    assert_eq!(run_trades(&engine, 3, &CellTime::Clock(NaiveTime::from_hms_opt(8, 1, 0).unwrap()), Direction::Long, &ExitRule::default(), &spec).len(), 1);
+}
+
+#[test]
+fn exit_rule_test() {
+    use chrono::NaiveDateTime;
+    use crate::exits::{simulate_exit, ExitGrid, ExitReason, ExitRule, StopDistance};
+    use crate::strategy::Fill;
+    use crate::bars::BarSeries;
+    use crate::window::WindowEngine;
+    use crate::conditions::Always;
+    let start = NaiveDateTime::parse_from_str("2021-01-05 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+    let datetimes: Vec<NaiveDateTime> = (0..10).map(|i| start + chrono::Duration::minutes(i)).collect();
+    let values = vec![1.0, 2.0, 3.0, 1.5, 0.5, 2.5, 4.0, 3.0, 3.0, 3.0];
+    // Each bar opens at the previous close and trades 0.5 either side of its close
+    let opens: Vec<f64> = [1.0].iter().chain(values[..9].iter()).cloned().collect();
+    let bars_with = |range: f64, open: Vec<f64>| BarSeries::new(
+        datetimes.iter().map(crate::bars::to_timestamp).collect(), open, values.iter().map(|x| x + range).collect(),
+        values.iter().map(|x| x - range).collect(), values.clone(), vec![0.; values.len()]);
+    let bars_with_range = |range: f64| bars_with(range, opens.clone());
+    let engine = WindowEngine::new(bars_with_range(0.5), &Always);
+
+    // Entry at 2.0 on bar 1, scheduled exit on bar 5, ticks of 0.25
+    let exit = |side: f64, rule: ExitRule| {
+        let e = simulate_exit(&engine, 1, 5, side, &rule, Fill::Close, 0.25);
+        (e.ix, e.price, e.reason)
+    };
+    let ticks = |n: f64| Some(StopDistance::Ticks(n));
+    assert_eq!(exit(1., ExitRule::default()), (5, 2.5, ExitReason::End));
+    assert_eq!(exit(1., ExitRule { stop_loss: ticks(4.), ..Default::default() }), (3, 1.0, ExitReason::StopLoss));
+    assert_eq!(exit(1., ExitRule { take_profit: ticks(4.), ..Default::default() }), (2, 3.0, ExitReason::TakeProfit));
+    assert_eq!(exit(-1., ExitRule { stop_loss: ticks(4.), ..Default::default() }), (2, 3.0, ExitReason::StopLoss));
+    // Trails 0.5 under the 3.5 high of bar 2, then bar 3 trades through it
+    assert_eq!(exit(1., ExitRule { trailing_stop: ticks(2.), ..Default::default() }), (3, 3.0, ExitReason::TrailingStop));
+    assert_eq!(exit(1., ExitRule { time_stop: Some(2), ..Default::default() }), (3, 1.5, ExitReason::TimeStop));
+    // Stop and target both touched in bar 2 of a wider series: the stop fills first
+    let wide = WindowEngine::new(bars_with_range(2.), &Always);
+    let both = ExitRule { stop_loss: ticks(4.), take_profit: ticks(4.), ..Default::default() };
+    assert_eq!(simulate_exit(&wide, 1, 5, 1., &both, Fill::Close, 0.25).reason, ExitReason::StopLoss);
+    // Bar 3 gapping down to open at 1.2 fills a 2.0 stop there
+    let mut gapped_opens = opens.clone();
+    gapped_opens[3] = 1.2;
+    let gapped = WindowEngine::new(bars_with(0.5, gapped_opens), &Always);
+    let stop = ExitRule { stop_loss: ticks(4.), ..Default::default() };
+    assert_eq!(simulate_exit(&gapped, 2, 5, 1., &stop, Fill::Close, 0.25).price, 1.2);
+    // Filling at the open holds bars 2 to 4 and exits at bar 5's open
+    let e = simulate_exit(&engine, 2, 5, 1., &ExitRule::default(), Fill::Open, 0.25);
+    assert_eq!((e.ix, e.last_bar, e.price), (5, 4, 0.5));
+
+    let grid = ExitGrid { stop_loss: vec![None, "8t".parse().ok(), "0.1%".parse().ok()], time_stop: vec![None, Some(30)],
+                          ..Default::default() };
+    assert_eq!(grid.rules().len(), 6);
+    assert_eq!(grid.rules()[5].to_string(), "sl=0.1% tm=30");
+    assert!(grid.validate().is_empty());
+}
+
+#[test]
+fn window_engine_test() {
+    use chrono::NaiveDateTime;
+    use crate::bars::BarSeries;
+    use crate::window::{CellTime, WindowEngine};
+    use crate::conditions::Always;
+    let day1 = NaiveDateTime::parse_from_str("2021-01-05 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+    let day2 = NaiveDateTime::parse_from_str("2021-01-06 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+    // Day 2 is missing its 08:03 bar, so an 08:00 -> 08:03 window only exists on day 1
+    let datetimes: Vec<NaiveDateTime> = (0..5).map(|i| day1 + chrono::Duration::minutes(i))
+        .chain([0, 1, 2, 4].iter().map(|&i| day2 + chrono::Duration::minutes(i)))
+        .collect();
+    let values: Vec<f64> = (0..datetimes.len()).map(|i| ((i * 7) % 5) as f64).collect();
+    let engine = WindowEngine::new(BarSeries::from_closes(datetimes, values.clone()), &Always);
+
+    let (start, end) = (CellTime::Clock(NaiveTime::from_hms_opt(8, 0, 0).unwrap()), CellTime::Clock(NaiveTime::from_hms_opt(8, 3, 0).unwrap()));
+    let windows: Vec<(usize, usize)> = engine.windows(&start, &end).collect();
+    assert_eq!(windows, vec![(0, 3)]);
+    for lo in 0..values.len() {
+        for hi in lo..values.len() {
+            let slice = &values[lo..=hi];
+            assert_eq!(engine.range_low(lo, hi), slice.iter().cloned().fold(f64::INFINITY, f64::min));
+            assert_eq!(engine.range_high(lo, hi), slice.iter().cloned().fold(f64::NEG_INFINITY, f64::max));
+        }
+    }
+}
+
+#[test]
+fn metric_set_test() {
+    use crate::metrics::{MetricSet, Observations};
+    let mut obs = Observations::default();
+    for r in [2.0, -1.0, 3.0, -2.0] {
+        obs.push(r, r.min(0.), r.max(0.));
+    }
+    let metrics = MetricSet::from_names(&["mean", "median", "hit_rate", "profit_factor", "expectancy", "calmar"], 252.).unwrap();
+    assert_eq!(metrics.compute(&obs), vec![0.5, 0.5, 0.5, 5.0 / 3.0, 0.5, 0.5 * 252. / 2.]);
+    assert_eq!(metrics.field_names(false)[5..7], ["mean".to_owned(), "median".to_owned()]);
+    assert!(MetricSet::from_names(&["sharp"], 252.).is_err());
+}
+
+#[test]
+fn contract_registry_test() {
+    use crate::analysis::window_end_time;
+    use crate::contracts::{ContractRegistry, Units};
+    use crate::window::CellTime;
+    let zn = ContractRegistry::default().get("ZN").unwrap().clone();
+    assert_eq!(zn.point_value(), 1000.);
+    assert_eq!(zn.unit_scale(Units::Ticks), 64.);
+    assert_eq!((zn.session_close, zn.timezone.as_str()), (NaiveTime::from_hms_opt(16, 0, 0).unwrap(), "America/Chicago"));
+    // The overnight session is only closed from 16:00 to 17:00
+    let time = |h: u32, m: u32| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+    assert!(zn.in_session(&time(7, 0)) && zn.in_session(&time(17, 0)) && !zn.in_session(&time(16, 30)));
+
+    let registry = ContractRegistry::from_file("config/contracts.yaml").unwrap();
+    let fgbl = registry.get("FGBL").unwrap();
+    assert_eq!(fgbl.unit_scale(Units::Currency), 1000.);
+    // Windows starting before the 01:15 open aren't traded
+    assert!(window_end_time(&CellTime::Clock(time(1, 0)), 30, fgbl).is_none());
+    assert_eq!(window_end_time(&CellTime::Clock(time(1, 15)), 30, fgbl), Some(CellTime::Clock(time(1, 45))));
+    assert!(registry.get("ZZ").is_err());
+}
+
+#[test]
+fn trading_calendar_test() {
+    use bdays::HolidayCalendar;
+    use chrono::NaiveDate;
+    use crate::calendars::CalendarRegistry;
+    let date = |d: u32, m: u32| NaiveDate::from_ymd_opt(2021, m, d).unwrap();
+    let registry = CalendarRegistry::from_file("config/calendars.yaml").unwrap();
+
+    // Eurex closes Good Friday and Easter Monday, which US settlement trades
+    let eurex = registry.get("eurex").unwrap();
+    assert_eq!(eurex.advance_bdays(date(1, 4), 1), date(6, 4));
+    assert_eq!(registry.get("us_settlement").unwrap().advance_bdays(date(1, 4), 1), date(2, 4));
+
+    // CME rates adds Good Friday, trades Columbus Day and closes early the day after Thanksgiving
+    let cme = registry.get("cme_rates").unwrap();
+    assert!(!cme.is_bday(date(2, 4)) && cme.is_bday(date(11, 10)));
+    assert_eq!(cme.advance_bdays(date(8, 10), 1), date(11, 10));
+    assert_eq!(cme.early_close(date(26, 11)), Some(NaiveTime::from_hms_opt(13, 0, 0).unwrap()));
+    assert_eq!(cme.early_close(date(24, 11)), None);
+    assert!(registry.get("lme").is_err());
+}
+
+#[test]
+fn walk_forward_folds_test() {
+    use chrono::NaiveDate;
+    use crate::metrics::MetricSet;
+    use crate::walkforward::WalkForwardConfig;
+    let events: Vec<NaiveDate> = [5, 12, 19, 26].iter().map(|&d| NaiveDate::from_ymd_opt(2021, 1, d).unwrap()).collect();
+    let config = WalkForwardConfig { train_events: 2, test_events: 1, ..Default::default() };
+    let folds = config.folds(&events);
+    assert_eq!(folds.len(), 2);
+    // Days split halfway between releases, so the 16th onwards belongs to the 19th
+    assert_eq!(folds[0].test, NaiveDate::from_ymd_opt(2021, 1, 16).unwrap()..=NaiveDate::from_ymd_opt(2021, 1, 22).unwrap());
+    assert_eq!(*folds[1].train.start(), NaiveDate::from_ymd_opt(2021, 1, 9).unwrap());
+    assert_eq!(folds[1].train_events, events[1]..=events[2]);
+    assert_eq!(*folds[1].test.end(), NaiveDate::MAX);
+
+    let anchored = WalkForwardConfig { anchored: true, ..config };
+    assert_eq!(anchored.folds(&events)[1].train_events, events[0]..=events[2]);
+    assert_eq!(MetricSet::default().column("net_max_drawdown", true), Some(5));
+    assert_eq!(MetricSet::default().column("net_sharpe", false), None);
+}
+
+#[test]
+fn significance_test() {
+    use rand::prelude::*;
+    use rand::rngs::StdRng;
+    use crate::significance::*;
+    assert!((normal_cdf(1.959964) - 0.975).abs() < 1e-6);
+    assert!((inverse_normal_cdf(0.975) - 1.959964).abs() < 1e-6);
+    assert!((inverse_normal_cdf(0.001) + 3.090232).abs() < 1e-6);
+    let adjusted = benjamini_hochberg(&[0.01, 0.04, f64::NAN, 0.03]);
+    assert_eq!(adjusted[..2], [0.03, 0.04]);
+    assert!(adjusted[2].is_nan());
+
+    // Pure noise: no cell should look significant and selection should be a coin flip
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut rows: Vec<Vec<f64>> = (0..100).map(|_| (0..240).map(|_| rng.gen::<f64>() - 0.5).collect()).collect();
+    // Only the best cells are tested jointly unless asked for all of them
+    assert_eq!(serde_yaml::from_str::<SignificanceConfig>("{}").unwrap().top_n, 500);
+    let config = SignificanceConfig { bootstrap_samples: 300, block_length: 5., partitions: 8, net: false, seed: 3,
+                                          top_n: 0 };
+    let daily: Vec<&[f64]> = rows.iter().map(|x| x.as_slice()).collect();
+    let pbo = probability_of_overfitting(&daily, 8);
+    assert!(pbo > 0.2 && pbo < 0.8, "pbo {}", pbo);
+    let (rc, spa) = reality_check(&daily, &config);
+    assert!(rc > 0.1 && spa > 0.1, "rc {} spa {}", rc, spa);
+
+    // One cell with a real edge
+    rows[0].iter_mut().for_each(|x| *x += 0.2);
+    let daily: Vec<&[f64]> = rows.iter().map(|x| x.as_slice()).collect();
+    let (rc, spa) = reality_check(&daily, &config);
+    assert!(rc < 0.05 && spa < 0.05, "rc {} spa {}", rc, spa);
+    assert!(probability_of_overfitting(&daily, 8) < 0.1);
+}
+
+#[test]
+fn confidence_interval_test() {
+    use crate::bootstrap::{Confidence, ConfidenceConfig};
+    use crate::metrics::{MetricSet, Observations};
+    let mut obs = Observations::default();
+    for i in 0..60 {
+        let r = ((i * 37) % 11) as f64 - 4.;
+        obs.push(r, r.min(0.), r.max(0.));
+    }
+    let config: ConfidenceConfig = serde_yaml::from_str("{ metrics: [mean, hit_rate], level: 0.9, samples: 500, seed: 1 }").unwrap();
+    let confidence = Confidence { metrics: MetricSet::from_names(&config.metrics, 252.).unwrap(), config };
+    assert_eq!(confidence.field_names(), ["mean lower", "mean upper", "hit rate lower", "hit rate upper"]);
+
+    let bounds = confidence.intervals(&obs, (30_u64, "08:30"));
+    let point = MetricSet::from_names(&["mean", "hit_rate"], 252.).unwrap().compute(&obs);
+    assert!(bounds[0] < point[0] && point[0] < bounds[1], "{:?} {:?}", bounds, point);
+    assert!(bounds[2] < point[1] && point[1] < bounds[3], "{:?} {:?}", bounds, point);
+    // Seeded per cell, so reproducible regardless of evaluation order
+    assert_eq!(bounds, confidence.intervals(&obs, (30_u64, "08:30")));
+    assert_ne!(bounds, confidence.intervals(&obs, (35_u64, "08:30")));
+}
+
+#[test]
+fn condition_algebra_test() {
+    use chrono::{NaiveDate, NaiveDateTime};
+    use crate::bars::BarSeries;
+    use crate::events::{Event, EventCalendar};
+    use crate::calendars::TradingCalendar;
+    // One bar a day, Monday 2021-01-04 to Friday 2021-01-15
+    let start = NaiveDate::from_ymd_opt(2021, 1, 4).unwrap().and_hms_opt(8, 0, 0).unwrap();
+    let datetimes: Vec<NaiveDateTime> = (0..12).map(|i| start + chrono::Duration::days(i)).collect();
+    let bars = BarSeries::from_closes(datetimes, vec![1.; 12]);
+    let mut calendar = EventCalendar::default();
+    calendar.insert("NFP".to_owned(), vec![Event::at(NaiveDate::from_ymd_opt(2021, 1, 8).unwrap().and_hms_opt(8, 30, 0).unwrap()),
+                                            Event::at(NaiveDate::from_ymd_opt(2021, 1, 15).unwrap().and_hms_opt(8, 30, 0).unwrap())]);
+    calendar.insert("FOMC".to_owned(), vec![Event::at(NaiveDate::from_ymd_opt(2021, 1, 13).unwrap().and_hms_opt(14, 0, 0).unwrap())]);
+    let evaluate = |yaml: &str| -> Vec<usize> {
+        let c: ConditionConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(c.validate().is_empty());
+        c.build("NFP", &calendar, &TradingCalendar::default()).unwrap().evaluate(&bars).iter().enumerate()
+            .filter(|(_, x)| **x).map(|(i, _)| i).collect()
+    };
+
+    // Day of NFP and not FOMC week
+    assert_eq!(evaluate("
+kind: and
+conditions:
+  - kind: day_of
+  - kind: not
+    condition: { kind: week_of, event: FOMC }
+"), vec![4]);
+    assert_eq!(evaluate("
+kind: or
+conditions: [{ kind: day_of }, { kind: day_of, event: FOMC }]
+"), vec![4, 9, 11]);
+    // The NFP on the 15th falls in FOMC week
+    assert_eq!(evaluate("
+kind: xor
+conditions: [{ kind: day_of }, { kind: week_of, event: FOMC }]
+"), vec![4, 7, 8, 9, 10]);
+
+    let empty: ConditionConfig = serde_yaml::from_str("{ kind: not, condition: { kind: or, conditions: [] } }").unwrap();
+    assert_eq!(empty.validate().len(), 1);
+    let unknown: ConditionConfig = serde_yaml::from_str("{ kind: week_of, event: CPI }").unwrap();
+    assert!(unknown.build("NFP", &calendar, &TradingCalendar::default()).is_err());
+}
+
+#[test]
+fn event_relative_windows_test() {
+    use chrono::{NaiveDate, NaiveDateTime};
+    use crate::bars::BarSeries;
+    use crate::conditions::Always;
+    use crate::window::{CellTime, WindowEngine};
+    // Releases at 08:30 on day 1 and 10:00 on day 2, with bars every minute from 08:00 to 10:59
+    let days = [NaiveDate::from_ymd_opt(2021, 1, 5).unwrap(), NaiveDate::from_ymd_opt(2021, 1, 6).unwrap()];
+    let datetimes: Vec<NaiveDateTime> = days.iter()
+        .flat_map(|d| (0..180).map(move |i| d.and_hms_opt(8, 0, 0).unwrap() + chrono::Duration::minutes(i)))
+        .collect();
+    let bars = BarSeries::from_closes(datetimes.clone(), vec![1.; datetimes.len()]);
+    let releases = [days[0].and_hms_opt(8, 30, 0).unwrap(), days[1].and_hms_opt(10, 0, 0).unwrap(), NaiveDate::from_ymd_opt(2021, 1, 7).unwrap().and_hms_opt(8, 30, 0).unwrap()];
+    let engine = WindowEngine::new(bars, &Always).with_releases(&releases);
+
+    let times = |start: CellTime, end: CellTime| -> Vec<(NaiveDateTime, NaiveDateTime)> {
+        engine.windows(&start, &end).map(|(i, j)| (datetimes[i], datetimes[j])).collect()
+    };
+    assert_eq!(times(CellTime::Release(-30), CellTime::Release(15)),
+               vec![(days[0].and_hms_opt(8, 0, 0).unwrap(), days[0].and_hms_opt(8, 45, 0).unwrap()),
+                    (days[1].and_hms_opt(9, 30, 0).unwrap(), days[1].and_hms_opt(10, 15, 0).unwrap())]);
+    // Day 2's exit would be past the last bar
+    assert_eq!(times(CellTime::Release(0), CellTime::Release(60)).len(), 1);
+    assert_eq!(CellTime::Release(-30).to_string(), "T-30");
+    assert_eq!(CellTime::Release(0).to_string(), "T+0");
+}
+
+#[test]
+fn calendar_conditions_test() {
+    use chrono::{NaiveDate, NaiveDateTime};
+    use crate::bars::BarSeries;
+    use crate::events::{Event, EventCalendar};
+    use crate::calendars::TradingCalendar;
+    // One bar a day from Monday 2021-01-25 to Friday 2021-02-12
+    let start = NaiveDate::from_ymd_opt(2021, 1, 25).unwrap().and_hms_opt(8, 0, 0).unwrap();
+    let datetimes: Vec<NaiveDateTime> = (0..19).map(|i| start + chrono::Duration::days(i)).collect();
+    let bars = BarSeries::from_closes(datetimes.clone(), vec![1.; 19]);
+    let release = |d: u32, m: u32| Event::at(NaiveDate::from_ymd_opt(2021, m, d).unwrap().and_hms_opt(8, 30, 0).unwrap());
+    let mut calendar = EventCalendar::default();
+    calendar.insert("IJC".to_owned(), vec![release(28, 1), release(4, 2), release(11, 2)]);
+    calendar.insert("FOMC".to_owned(), vec![release(27, 1)]);
+    calendar.insert("GDP".to_owned(), vec![release(28, 1)]);
+    calendar.insert("NFP".to_owned(), vec![release(5, 2)]);
+    let evaluate = |yaml: &str| -> Vec<NaiveDate> {
+        let c: ConditionConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(c.validate().is_empty());
+        let selected = c.build("IJC", &calendar, &TradingCalendar::default()).unwrap().evaluate(&bars);
+        datetimes.iter().zip(selected).filter(|(_, x)| *x).map(|(dt, _)| dt.date()).collect()
+    };
+    let date = |d: u32, m: u32| NaiveDate::from_ymd_opt(2021, m, d).unwrap();
+
+    // Two business days after FOMC skip the weekend
+    assert_eq!(evaluate("{ kind: within, event: FOMC, after: 2 }"), vec![date(27, 1), date(28, 1), date(29, 1)]);
+    assert_eq!(evaluate("{ kind: within, event: NFP, before: 1, is_bus_days: false }"), vec![date(4, 2), date(5, 2)]);
+    assert_eq!(evaluate("{ kind: first_of_month }"), vec![date(28, 1), date(4, 2)]);
+    // IJC releases clashing with another event, then those that don't
+    assert_eq!(evaluate("{ kind: and, conditions: [{ kind: day_of }, { kind: co_occurs }] }"), vec![date(28, 1)]);
+    assert_eq!(evaluate("
+kind: and
+conditions:
+  - kind: day_of
+  - kind: not
+    condition: { kind: co_occurs, events: [GDP, NFP] }
+"), vec![date(4, 2), date(11, 2)]);
+    assert_eq!(evaluate("{ kind: co_occurs, events: [IJC, GDP, FOMC], min_events: 2 }"), vec![date(28, 1)]);
+}
+
+#[test]
+fn bar_series_slicing_test() {
+    use chrono::{NaiveDate, NaiveDateTime};
+    use crate::bars::BarSeries;
+    // Bars at 08:00, 08:30 and 09:00 on three days
+    let date = |d: u32| NaiveDate::from_ymd_opt(2021, 1, d).unwrap();
+    let datetimes: Vec<NaiveDateTime> = [4, 5, 6].iter()
+        .flat_map(|&d| [(8, 0), (8, 30), (9, 0)].map(|(h, m)| date(d).and_hms_opt(h, m, 0).unwrap()))
+        .collect();
+    let bars = BarSeries::from_closes(datetimes, (0..9).map(|x| x as f64).collect());
+    assert_eq!(bars.days(), &[date(4), date(5), date(6)]);
+    assert_eq!(bars.day_range(1), 3..6);
+
+    // Slices share the columns and keep their own day index
+    let tail = bars.slice_dates(&(date(5)..=date(9)));
+    assert_eq!((tail.len(), tail.close()[0], tail.days()), (6, 3., &[date(5), date(6)][..]));
+    let part = bars.slice(4..8);
+    assert_eq!((part.days(), part.day_range(0), part.day_range(1)), (&[date(5), date(6)][..], 0..2, 2..4));
+    assert!(bars.slice_dates(&(date(7)..=date(9))).is_empty());
+
+    let mornings: Vec<Vec<f64>> = bars.slice_times(NaiveTime::from_hms_opt(8, 15, 0).unwrap(), NaiveTime::from_hms_opt(9, 0, 0).unwrap())
+        .map(|x| x.close().to_vec())
+        .collect();
+    assert_eq!(mornings, vec![vec![1.], vec![4.], vec![7.]]);
+    assert_eq!(part.slice_times(NaiveTime::from_hms_opt(8, 15, 0).unwrap(), NaiveTime::from_hms_opt(8, 45, 0).unwrap()).count(), 2);
+
+    let filtered = bars.filter_days(|d| d != date(5));
+    assert_eq!((filtered.close(), filtered.days()), (&[0., 1., 2., 6., 7., 8.][..], &[date(4), date(6)][..]));
+}
+
+#[test]
+fn load_bars_test() {
+    use chrono::NaiveDate;
+    use crate::loader::{load_bars, ColumnMap};
+    let dir = std::env::temp_dir();
+    let (positional, mapped) = (dir.join("backtesting_load_bars_test.csv"), dir.join("backtesting_load_bars_mapped_test.csv"));
+    std::fs::write(&positional, "datetime_str,open,high,low,close,volume,spread
+2021-01-04 08:00:00,130.0,130.5,129.5,130.25,254
+2021-01-04 08:01:00,130.25,130.5,130.0,130.0,195,0.015625
+").unwrap();
+    let bars = load_bars(positional.to_str().unwrap(), &ColumnMap::default()).unwrap();
+    assert_eq!(bars.datetime(1), NaiveDate::from_ymd_opt(2021, 1, 4).unwrap().and_hms_opt(8, 1, 0).unwrap());
+    assert_eq!((bars.high()[0], bars.close()[1], bars.volume()[1]), (130.5, 130.0, 195.));
+    assert_eq!(bars.spreads(), &[0., 0.015625]);
+    // A value in the spread column that isn't a number is an invalid row, not a missing spread
+    std::fs::write(&positional, "datetime_str,open,high,low,close,volume,spread
+2021-01-04 08:00:00,130.0,130.5,129.5,130.25,254,n/a
+").unwrap();
+    let err = load_bars(positional.to_str().unwrap(), &ColumnMap::default()).unwrap_err().to_string();
+    assert!(err.contains("line 2: invalid or missing spread"), "{}", err);
+
+    // Columns found by name, in any order, with epoch millisecond timestamps
+    std::fs::write(&mapped, "Close,Volume,Time,Low,High,Open
+130.25,254,1609747200000,129.5,130.5,130.0
+").unwrap();
+    let columns: ColumnMap = serde_yaml::from_str("{datetime: Time, open: Open, high: High, low: Low, close: Close,
+        volume: Volume, spread: null, datetime_format: epoch_ms}").unwrap();
+    let bars = load_bars(mapped.to_str().unwrap(), &columns).unwrap();
+    assert_eq!(bars.datetime(0), NaiveDate::from_ymd_opt(2021, 1, 4).unwrap().and_hms_opt(8, 0, 0).unwrap());
+    assert_eq!((bars.open()[0], bars.low()[0], bars.close()[0]), (130.0, 129.5, 130.25));
+    assert!(!bars.has_spread());
+
+    // A bad row is reported by line rather than panicking
+    std::fs::write(&positional, "datetime_str,open,high,low,close,volume
+2021-01-04 08:00:00,130.0,130.5,129.5,130.25,254
+2021-01-04 8:01,130.25,130.5,130.0,130.0,195
+").unwrap();
+    let e = load_bars(positional.to_str().unwrap(), &ColumnMap::default()).unwrap_err();
+    assert!(e.to_string().contains("line 3: invalid or missing datetime"), "{}", e);
+    assert!(load_bars(mapped.to_str().unwrap(), &ColumnMap::default()).is_err());
+}
+
+#[test]
+fn data_quality_test() {
+    use crate::loader::{load_bars_skipping_invalid, ColumnMap};
+    use crate::quality::{check_bars, validate_bars, IssueKind, QualityConfig};
+    let path = std::env::temp_dir().join("backtesting_data_quality_test.csv");
+    std::fs::write(&path, "datetime,open,high,low,close,volume
+2021-01-04 08:00:00,130.0,130.5,129.5,130.25,254
+2021-01-04 08:01:00,130.25,130.5,130.0,130.0,195
+2021-01-04 08:01:00,130.25,130.5,130.0,130.0,195
+2021-01-04 08:02,130.0,130.5,129.5,130.25,254
+2021-01-04 08:00:30,130.0,130.5,129.5,130.25,254
+2021-01-04 08:03:00,130.0,129.5,130.5,130.25,254
+2021-01-04 08:04:00,130.0,130.5,129.5,0,254
+2021-01-04 08:05:00,130.0,150.5,129.5,150.25,254
+2021-01-04 08:06:00,130.0,130.5,129.5,130.25,254
+2021-01-04 08:09:00,130.0,130.5,129.5,130.25,254
+2021-01-04 09:30:00,130.0,130.5,129.5,130.25,254
+").unwrap();
+    let (bars, invalid) = load_bars_skipping_invalid(path.to_str().unwrap(), &ColumnMap::default()).unwrap();
+    assert_eq!((bars.len(), invalid.len(), invalid[0].line), (10, 1, 5));
+
+    let warn: QualityConfig = serde_yaml::from_str("{}").unwrap();
+    let (kept, report) = check_bars(bars.clone(), invalid.clone(), &warn);
+    // Out of order bars are left out even when warning, so the series stays sorted
+    assert_eq!((kept.len(), report.dropped), (8, 2));
+    assert!(kept.timestamps().windows(2).all(|x| x[0] < x[1]));
+    // The zero close is also an invalid range, the spike jumps both ways, the unparseable 08:02 bar
+    // is missing and the 09:30 bar is after a session break
+    assert_eq!(report.summary(), "1 unparseable, 1 duplicate, 1 non_monotonic, 2 missing_minutes, \
+                                  1 non_positive_price, 2 invalid_range, 2 jump");
+    let gaps: Vec<&str> = report.issues.iter().filter(|x| x.kind == IssueKind::MissingMinutes).map(|x| x.detail.as_str()).collect();
+    assert_eq!(gaps, ["1 minutes missing after 08:01:00", "2 minutes missing after 08:06:00"]);
+
+    // Dropping the spike measures the next bar from the last close kept
+    let drop: QualityConfig = serde_yaml::from_str("{policy: drop, checks: {missing_minutes: warn}}").unwrap();
+    let (kept, report) = check_bars(bars.clone(), invalid.clone(), &drop);
+    assert_eq!((kept.len(), report.dropped, report.count(IssueKind::Jump)), (5, 5, 1));
+    assert!(kept.close().iter().all(|&x| x == 130.25 || x == 130.));
+
+    let fail: QualityConfig = serde_yaml::from_str("{checks: {non_monotonic: fail}}").unwrap();
+    let e = validate_bars(bars, invalid, &fail).unwrap_err();
+    assert!(e.to_string().starts_with("Data quality check failed on non_monotonic"), "{}", e);
+    assert_eq!(serde_yaml::from_str::<QualityConfig>("{checks: {missing_minutes: drop}}").unwrap().validate().len(), 1);
+}
+
+#[test]
+fn bar_cache_test() {
+    use crate::cache::load_bars_cached;
+    use crate::loader::ColumnMap;
+    let dir = std::env::temp_dir().join("backtesting_bar_cache_test");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let (source, cache_dir) = (dir.join("bars.csv"), dir.join("cache"));
+    let (source, cache_dir) = (source.to_str().unwrap(), cache_dir.to_str().unwrap());
+    std::fs::write(source, "datetime,open,high,low,close,volume,spread
+2021-01-04 08:00:00,130.0,130.5,129.5,130.25,254,0.015625
+2021-01-05 08:01:00,130.25,130.5,130.0,130.0,195,
+").unwrap();
+    let (parsed, _) = load_bars_cached(source, &ColumnMap::default(), cache_dir, false).unwrap();
+    assert_eq!(std::fs::read_dir(cache_dir).unwrap().count(), 1);
+    let (cached, _) = load_bars_cached(source, &ColumnMap::default(), cache_dir, false).unwrap();
+    assert_eq!((cached.timestamps(), cached.close(), cached.spreads()), (parsed.timestamps(), parsed.close(), parsed.spreads()));
+    assert_eq!(cached.days(), parsed.days());
+    assert_eq!((cached.day_range(1), cached.slice(1..2).days()), (parsed.day_range(1), parsed.slice(1..2).days()));
+
+    // Rewriting the source replaces its cache rather than reading a stale one
+    std::fs::write(source, "datetime,open,high,low,close,volume,spread
+2021-01-04 08:00:00,130.0,130.5,129.5,130.50,254,0.015625
+").unwrap();
+    let (reparsed, _) = load_bars_cached(source, &ColumnMap::default(), cache_dir, false).unwrap();
+    assert_eq!((reparsed.len(), reparsed.close()[0]), (1, 130.5));
+    assert_eq!(std::fs::read_dir(cache_dir).unwrap().count(), 1);
+}
+
+#[test]
+#[cfg(feature = "columnar")]
+fn columnar_round_trip_test() {
+    use chrono::NaiveDate;
+    use crate::loader::{load_bars, load_event_calendar, ColumnMap};
+    use crate::strategy::{FieldType, FieldValue, FieldsToStrings, FieldsToValues};
+    use crate::utils::{write_table, OutputFormat};
+    let dt = |d: u32, h: u32, m: u32| NaiveDate::from_ymd_opt(2021, 1, d).unwrap().and_hms_opt(h, m, 0).unwrap();
+    struct Bar(chrono::NaiveDateTime, [f64; 5]);
+    impl FieldsToStrings for Bar {
+        fn fields_to_strings(&self) -> Vec<String> { self.fields_to_values().iter().map(|x| format!("{:?}", x)).collect() }
+    }
+    impl FieldsToValues for Bar {
+        fn field_types(_: usize) -> Vec<FieldType> { [vec![FieldType::DateTime], vec![FieldType::Float; 5]].concat() }
+        fn fields_to_values(&self) -> Vec<FieldValue> {
+            [vec![self.0.into()], self.1.iter().map(|&x| x.into()).collect()].concat()
+        }
+    }
+    struct Release(&'static str, chrono::NaiveDateTime, &'static str, f64);
+    impl FieldsToStrings for Release {
+        fn fields_to_strings(&self) -> Vec<String> { self.fields_to_values().iter().map(|x| format!("{:?}", x)).collect() }
+    }
+    impl FieldsToValues for Release {
+        fn field_types(_: usize) -> Vec<FieldType> { vec![FieldType::Text, FieldType::DateTime, FieldType::Text, FieldType::Float] }
+        fn fields_to_values(&self) -> Vec<FieldValue> {
+            vec![self.0.to_owned().into(), self.1.into(), self.2.to_owned().into(), self.3.into()]
+        }
+    }
+    let dir = std::env::temp_dir();
+    let bars = vec![Bar(dt(4, 8, 0), [130., 130.5, 129.5, 130.25, 254.]), Bar(dt(4, 8, 1), [130.25, 130.5, 130., 130., 195.])];
+    let events = vec![Release("NFP", dt(8, 8, 30), "-140K", 50000.), Release("NFP", dt(8, 9, 30), "49K", 105000.)];
+    for format in [OutputFormat::Parquet, OutputFormat::Arrow] {
+        let stem = dir.join(format!("backtesting_columnar_{}", format.extension()));
+        let stem = stem.to_str().unwrap();
+        write_table(&bars, &["datetime", "open", "high", "low", "close", "volume"], stem, format).unwrap();
+        let loaded = load_bars(&format!("{}.{}", stem, format.extension()), &ColumnMap::default()).unwrap();
+        assert_eq!(loaded.datetime(1), dt(4, 8, 1));
+        assert_eq!((loaded.open()[0], loaded.high()[1], loaded.volume()[1]), (130., 130.5, 195.));
+        assert!(!loaded.has_spread());
+
+        // Event values are parsed from strings or read as numbers
+        let stem = format!("{}_events", stem);
+        write_table(&events, &["name", "datetime", "actual", "forecast"], &stem, format).unwrap();
+        let calendar = load_event_calendar(&format!("{}.{}", stem, format.extension())).unwrap();
+        let nfp = &calendar["NFP"];
+        assert_eq!(nfp[0].datetime, dt(8, 8, 30));
+        assert_eq!((nfp[0].actual, nfp[1].forecast, nfp[1].surprise()), (Some(-140e3), Some(105e3), Some(-56e3)));
+        assert_eq!(nfp[0].currency, None);
+    }
+}
+
+#[test]
+#[cfg(feature = "columnar")]
+fn columnar_schema_test() {
+    use arrow::datatypes::DataType;
+    use arrow::ipc::reader::FileReader;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use crate::exits::ExitRule;
+    use crate::strategy::{Direction, StrategyResult};
+    use crate::utils::{write_table, OutputFormat};
+    use crate::window::CellTime;
+    // Whole number metrics, as with ticks, are still floats, and counts are integers
+    let result = || StrategyResult {
+        interval: 30,
+        start_time: CellTime::Clock(NaiveTime::from_hms_opt(8, 30, 0).unwrap()),
+        end_time: CellTime::Clock(NaiveTime::from_hms_opt(9, 0, 0).unwrap()),
+        direction: Direction::Long,
+        exit_rule: ExitRule::default(),
+        metrics: vec![2., 0.25, -1.],
+        n_obs: 12,
+    };
+    let cols = ["interval", "start time", "end time", "direction", "exit rule", "mean", "hit rate", "median", "n obs"];
+    let expected = [DataType::Int64, DataType::Utf8, DataType::Utf8, DataType::Utf8, DataType::Utf8, DataType::Float64,
+                    DataType::Float64, DataType::Float64, DataType::Int64];
+    let stem = std::env::temp_dir().join("backtesting_columnar_schema");
+    let stem = stem.to_str().unwrap();
+    for format in [OutputFormat::Parquet, OutputFormat::Arrow] {
+        write_table(&vec![result()], &cols, stem, format).unwrap();
+        let file = std::fs::File::open(format!("{}.{}", stem, format.extension())).unwrap();
+        let schema = match format {
+            OutputFormat::Parquet => ParquetRecordBatchReaderBuilder::try_new(file).unwrap().schema().clone(),
+            _ => FileReader::try_new(file, None).unwrap().schema(),
+        };
+        let types: Vec<DataType> = schema.fields().iter().map(|x| x.data_type().clone()).collect();
+        assert_eq!(types, expected);
+    }
+    // Rows with the wrong number of fields for the header aren't written
+    assert!(write_table(&vec![result()], &cols[..8], stem, OutputFormat::Parquet).is_err());
+}
+
+#[test]
+fn event_fields_test() {
+    use chrono::NaiveDate;
+    use crate::events::{get_event_calendar, Impact, ReleaseFilter};
+    let path = std::env::temp_dir().join("backtesting_event_fields_test.csv");
+    std::fs::write(&path, "name,datetime,impact,currency,actual,forecast,previous
+NFP,2021-01-08 08:30:00,HIGH,USD,-140K,50K,336K
+NFP,2021-02-05 08:30:00,HIGH,USD,49K,105K,-227K
+NFP,2021-03-05 08:30:00,HIGH,USD,379K,200K,166K
+CPI,2021-01-13 08:30:00,MED,USD,0.4%,0.4%,0.2%
+BoE,2021-02-04 07:00:00,HIGH,GBP,,,
+").unwrap();
+    let calendar = get_event_calendar(path.to_str().unwrap()).unwrap();
+    let nfp = &calendar["NFP"];
+    assert_eq!((nfp[0].impact, nfp[0].currency.as_deref()), (Impact::High, Some("USD")));
+    assert_eq!((nfp[0].actual, nfp[0].forecast, nfp[0].surprise()), (Some(-140e3), Some(50e3), Some(-190e3)));
+    // Surprises of -190K, -56K and 179K have a sample standard deviation of 186.79K
+    assert!((nfp[2].standardized_surprise.unwrap() - 179. / 186.7895).abs() < 1e-4);
+    assert_eq!(calendar["CPI"][0].surprise(), Some(0.));
+    assert_eq!(calendar["BoE"][0].surprise(), None);
+
+    let filter = |yaml: &str| -> Vec<NaiveDate> {
+        let f: ReleaseFilter = serde_yaml::from_str(yaml).unwrap();
+        let mut v: Vec<NaiveDate> = calendar.values().flatten().filter(|x| f.matches(x)).map(|x| x.datetime.date()).collect();
+        v.sort();
+        v
+    };
+    assert_eq!(filter("{ min_impact: high, currencies: [GBP] }"), vec![NaiveDate::from_ymd_opt(2021, 2, 4).unwrap()]);
+    assert_eq!(filter("{ surprise: positive }"), vec![NaiveDate::from_ymd_opt(2021, 3, 5).unwrap()]);
+    assert_eq!(filter("{ min_abs_surprise: 0.5 }").len(), 2);
+    assert_eq!(filter("{ min_impact: medium }").len(), 5);
+
+    std::fs::write(&path, "name,datetime,impact,currency,actual,forecast,previous
+NFP,2021-01-08 08:30:00,HIGH,USD,-140K,50K,336K
+NFP,2021-02-05 8:30,HIGH,USD,49K,105K,-227K
+").unwrap();
+    let err = get_event_calendar(path.to_str().unwrap()).unwrap_err().to_string();
+    assert!(err.contains("line 3") && err.contains("2021-02-05 8:30"), "{}", err);
+}
+
+#[test]
+fn surprise_regression_test() {
+    use crate::regression::ols;
+    // Noise that leaves the slope of y = 1 + 2x alone
+    let x = [-1., 0., 1., 2.];
+    let (beta, t_stat, r_squared) = ols(&x, &[-0.5, 0.5, 2.5, 5.5]).unwrap();
+    assert!((beta - 2.).abs() < 1e-12);
+    // Residuals 0.5, -0.5, -0.5, 0.5 over Sxx = 5 give a standard error of sqrt(1 / 2 / 5)
+    assert!((t_stat - 2. / 0.1_f64.sqrt()).abs() < 1e-9);
+    assert!((r_squared - 20. / 21.).abs() < 1e-12);
+    assert!(ols(&[1., 1., 1.], &[0., 1., 2.]).is_none());
+    assert!(ols(&[1., 2.], &[0., 1.]).is_none());
+    // Constant returns leave R² undefined and an exact line an infinite t-statistic
+    assert!(ols(&x, &[0.5, 0.5, 0.5, 0.5]).is_none());
+    assert!(ols(&x, &[-1., 1., 3., 5.]).is_none());
+    assert!(ols(&[0.1, 0.2, 0.3], &[0.3, 0.5, 0.7]).is_none());
+}
+
+#[test]
+fn timezone_conversion_test() {
+    use chrono::{NaiveDate, NaiveDateTime};
+    use chrono_tz::Tz;
+    use crate::bars::BarSeries;
+    use crate::events::{convert_calendar, Event, EventCalendar};
+    use crate::timezones::{convert, parse_timezone};
+    let (utc, chicago, new_york) = (Tz::UTC, parse_timezone("America/Chicago").unwrap(), Tz::America__New_York);
+    assert!(parse_timezone("Mars/Olympus_Mons").is_err());
+    let dt = |d: u32, m: u32, h: u32, min: u32| NaiveDate::from_ymd_opt(2021, m, d).unwrap().and_hms_opt(h, min, 0).unwrap();
+
+    // 08:30 Eastern releases are 07:30 Central either side of the March DST change, while a fixed
+    // 13:30 UTC bar moves an hour in local time
+    let mut calendar = EventCalendar::default();
+    calendar.insert("CPI".to_owned(), vec![Event::at(dt(10, 3, 8, 30)), Event::at(dt(17, 3, 8, 30))]);
+    let calendar = convert_calendar(calendar, new_york, chicago);
+    assert_eq!(calendar["CPI"].iter().map(|x| x.datetime).collect::<Vec<NaiveDateTime>>(),
+               vec![dt(10, 3, 7, 30), dt(17, 3, 7, 30)]);
+    let bars = BarSeries::from_closes(vec![dt(10, 3, 13, 30), dt(17, 3, 13, 30)], vec![1., 2.])
+        .convert_timezone(utc, chicago);
+    assert_eq!((0..bars.len()).map(|i| bars.datetime(i)).collect::<Vec<NaiveDateTime>>(), vec![dt(10, 3, 7, 30), dt(17, 3, 8, 30)]);
+
+    // 02:30 doesn't exist on the spring forward day, and 01:30 happens twice when clocks go back
+    assert_eq!(convert(dt(14, 3, 2, 30), chicago, utc), None);
+    assert_eq!(convert(dt(7, 11, 1, 30), chicago, utc), Some(dt(7, 11, 6, 30)));
+    let bars = BarSeries::from_closes((5..9).map(|h| dt(7, 11, h, 30)).collect(), vec![1., 2., 3., 4.])
+        .convert_timezone(utc, chicago);
+    assert_eq!((0..bars.len()).map(|i| bars.datetime(i)).collect::<Vec<NaiveDateTime>>(), vec![dt(7, 11, 0, 30), dt(7, 11, 1, 30), dt(7, 11, 2, 30)]);
+    assert_eq!(bars.close(), &[1., 2., 4.]);
+}
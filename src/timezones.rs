@@ -0,0 +1,33 @@
+use std::error::Error;
+use chrono::{NaiveDateTime, TimeZone};
+use chrono::offset::LocalResult;
+use chrono_tz::Tz;
+use simple_error::SimpleError;
+
+/// Zones the bar data and event calendar are recorded in, and the contract's exchange zone both are
+/// converted to on load. Clock times in the sweep and session hours are exchange wall clock times,
+/// so a window at 08:30 stays at 08:30 local time on both sides of a DST change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeZones
+{
+    pub data: Tz,
+    pub events: Tz,
+    pub exchange: Tz,
+}
+
+pub fn parse_timezone(name: &str) -> Result<Tz, Box<dyn Error>>
+{
+    name.parse::<Tz>().map_err(|e| Box::new(SimpleError::new(format!("Unknown timezone {}: {}", name, e))) as Box<dyn Error>)
+}
+
+/// Wall clock time `dt` in zone `from` as a wall clock time in zone `to`. Times in the hour repeated
+/// when clocks go back are taken as its first occurrence, and times skipped when they go forward
+/// don't exist, giving `None`.
+pub fn convert(dt: NaiveDateTime, from: Tz, to: Tz) -> Option<NaiveDateTime>
+{
+    if from == to { return Some(dt) }
+    match from.from_local_datetime(&dt) {
+        LocalResult::Single(x) | LocalResult::Ambiguous(x, _) => Some(x.with_timezone(&to).naive_local()),
+        LocalResult::None => None,
+    }
+}
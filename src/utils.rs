@@ -1,7 +1,7 @@
 use log::{error, info};
-use crate::BUS_DAY_CAL;
+use crate::calendars::TradingCalendar;
 pub use crate::vector_utils::*;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use std::fs::File;
 use std::io::prelude::*;
 use std::error::Error;
@@ -10,30 +10,11 @@ use csv::{ByteRecord, ReaderBuilder};
 use serde::de;
 use serde_derive::Deserialize;
 use bdays::HolidayCalendar;
-use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use chrono::{NaiveDate, NaiveTime};
 use std::cmp::Ordering;
 use simple_error::SimpleError;
 
 
-#[derive(Deserialize, Clone)]
-pub struct Row
-{
-    pub datetime_str: String,
-    pub open: f64,
-    pub high: f64,
-    pub low: f64,
-    pub close: f64,
-    pub volume: f64,
-}
-impl Row
-{
-    pub fn datetime(&self) -> NaiveDateTime {
-        NaiveDateTime::parse_from_str(&self.datetime_str,
-                                      "%Y-%m-%d %H:%M:%S")
-            .unwrap()
-    }
-}
-
 pub fn read_csv<T: de::DeserializeOwned>(file_name: &str) -> Result<Vec<T>, Box<dyn Error>> {
     info!("Reading CSV from {}", file_name);
     let mut file = File::open(file_name)?;
@@ -57,8 +38,8 @@ pub fn read_csv<T: de::DeserializeOwned>(file_name: &str) -> Result<Vec<T>, Box<
     Ok(v)
 }
 
-use crate::strategy::FieldsToStrings;
-pub fn write_csv<T: FieldsToStrings>(v: &Vec<T>, cols: &[&str], loc: &str) -> Result<(), Box<dyn Error>>
+use crate::strategy::{FieldsToStrings, FieldsToValues};
+pub fn write_csv<T: FieldsToStrings, S: AsRef<[u8]>>(v: &Vec<T>, cols: &[S], loc: &str) -> Result<(), Box<dyn Error>>
 {
     info!("Writing to csv");
     match v.len() {
@@ -80,17 +61,62 @@ pub fn write_csv<T: FieldsToStrings>(v: &Vec<T>, cols: &[&str], loc: &str) -> Re
     }
 }
 
-pub fn filter_timeseries_by_events<'a>(datetimes: Vec<&'a Row>, event_dates: &'a Vec<NaiveDate>,
-                                       back_threshold_bdays: u32, fwd_threshold_bdays: u32)
-    -> Vec<&'a Row>
+/// File format results are written in
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat
 {
-    let filter_dates:Vec<NaiveDate> = (-(back_threshold_bdays as i32)..=(fwd_threshold_bdays as i32)).map(|i| {
-        event_dates.iter().map(move |&x| BUS_DAY_CAL.advance_bdays(x, i))
-    })
-        .flatten()
-        .collect();
+    #[default]
+    Csv,
+    /// Snappy compressed Parquet, needs the columnar feature
+    Parquet,
+    /// Arrow IPC file, needs the columnar feature
+    Arrow,
+}
 
-    datetimes.into_iter().filter(|&x| filter_dates.contains(&x.datetime().date())).collect()
+impl OutputFormat
+{
+    pub fn extension(&self) -> &'static str
+    {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Parquet => "parquet",
+            OutputFormat::Arrow => "arrow",
+        }
+    }
+}
+
+/// Writes `v` to `stem` with the extension of `format`
+pub fn write_table<T: FieldsToStrings + FieldsToValues, S: AsRef<str>>(v: &Vec<T>, cols: &[S], stem: &str, format: OutputFormat)
+    -> Result<(), Box<dyn Error>>
+{
+    let cols: Vec<&str> = cols.iter().map(|x| x.as_ref()).collect();
+    let loc = format!("{}.{}", stem, format.extension());
+    match format {
+        OutputFormat::Csv => write_csv(v, &cols, &loc),
+        _ if v.is_empty() => {
+            let msg = format!("{} output has length zero", format.extension());
+            error!("{}", msg);
+            Err(Box::new(SimpleError::new(msg)))
+        },
+        #[cfg(feature = "columnar")]
+        OutputFormat::Parquet => crate::columnar::write_parquet(v, &cols, &loc),
+        #[cfg(feature = "columnar")]
+        OutputFormat::Arrow => crate::columnar::write_ipc(v, &cols, &loc),
+        #[cfg(not(feature = "columnar"))]
+        _ => Err(Box::new(SimpleError::new(format!("Writing {} needs the columnar feature", loc)))),
+    }
+}
+
+/// Days within `back_threshold_bdays` before to `fwd_threshold_bdays` after an event, in `calendar`'s
+/// business days
+pub fn event_days(event_dates: &[NaiveDate], back_threshold_bdays: u32, fwd_threshold_bdays: u32,
+                  calendar: &TradingCalendar) -> FxHashSet<NaiveDate>
+{
+    (-(back_threshold_bdays as i32)..=(fwd_threshold_bdays as i32)).flat_map(|i| {
+        event_dates.iter().map(move |&x| calendar.advance_bdays(x, i))
+    })
+        .collect()
 }
 
 pub fn time_range(start_time: (u32, u32, u32), end_time: (u32, u32, u32), step_mins: u64) -> Vec<NaiveTime>
@@ -98,8 +124,8 @@ pub fn time_range(start_time: (u32, u32, u32), end_time: (u32, u32, u32), step_m
     let (start_hr, start_min, start_sec) = start_time;
     let (end_hr, end_min, end_sec) = end_time;
 
-    let start_time_nt: NaiveTime = NaiveTime::from_hms(start_hr, start_min, start_sec);
-    let end_time_nt: NaiveTime = NaiveTime::from_hms(end_hr, end_min, end_sec);
+    let start_time_nt: NaiveTime = NaiveTime::from_hms_opt(start_hr, start_min, start_sec).unwrap();
+    let end_time_nt: NaiveTime = NaiveTime::from_hms_opt(end_hr, end_min, end_sec).unwrap();
 
     let mut v: Vec<NaiveTime> = vec![start_time_nt];
     let mut i: u64 = 0;
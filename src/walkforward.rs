@@ -0,0 +1,288 @@
+use std::error::Error;
+use std::ops::RangeInclusive;
+use log::info;
+use chrono::{Duration, NaiveDate};
+use rayon::prelude::*;
+use serde_derive::Deserialize;
+use simple_error::SimpleError;
+use crate::analysis::{analyse_cell, cell_observations};
+use crate::metrics::Observations;
+use crate::strategy::{metric_field_types, FieldType, FieldValue, FieldsToStrings, FieldsToValues, StrategyResult, StrategySpec,
+                      CELL_KEY_TYPES};
+use crate::window::{CellTime, WindowEngine};
+
+/// Walk-forward validation over an event's releases. Each fold sweeps the grid on a block of
+/// training events, keeps the best cells and trades them unchanged on the following test events.
+#[derive(Deserialize, Debug, Clone)]
+pub struct WalkForwardConfig
+{
+    /// Events each fold selects its cells on
+    #[serde(default = "default_train_events")]
+    pub train_events: usize,
+    /// Events each fold is tested on; test blocks follow each other without overlapping
+    #[serde(default = "default_test_events")]
+    pub test_events: usize,
+    /// Grow the training block from the first event instead of rolling it forward
+    #[serde(default)]
+    pub anchored: bool,
+    /// Best in-sample cells carried into each test block
+    #[serde(default = "default_top_n")]
+    pub top_n: usize,
+    /// Metric cells are ranked by, highest first, with a `net_` prefix for its net of cost value
+    #[serde(default = "default_select_by")]
+    pub select_by: String,
+}
+
+fn default_train_events() -> usize { 12 }
+fn default_test_events() -> usize { 3 }
+fn default_top_n() -> usize { 1 }
+fn default_select_by() -> String { "sharpe".to_owned() }
+
+impl Default for WalkForwardConfig
+{
+    fn default() -> Self
+    {
+        Self {
+            train_events: default_train_events(),
+            test_events: default_test_events(),
+            anchored: false,
+            top_n: default_top_n(),
+            select_by: default_select_by(),
+        }
+    }
+}
+
+/// Days traded in and events covered by one fold's training and test blocks
+pub struct Fold
+{
+    pub train: RangeInclusive<NaiveDate>,
+    pub test: RangeInclusive<NaiveDate>,
+    pub train_events: RangeInclusive<NaiveDate>,
+    pub test_events: RangeInclusive<NaiveDate>,
+}
+
+impl WalkForwardConfig
+{
+    pub fn validate(&self) -> Vec<String>
+    {
+        let mut errors: Vec<String> = Vec::new();
+        if self.train_events == 0 || self.test_events == 0 || self.top_n == 0 {
+            errors.push("walk_forward train_events, test_events and top_n must be positive".to_owned());
+        }
+        errors
+    }
+
+    /// Folds over sorted, distinct `event_dates`. Every day belongs to its nearest event, splitting
+    /// halfway between consecutive releases, so the days around a release stay in its block.
+    pub fn folds(&self, event_dates: &[NaiveDate]) -> Vec<Fold>
+    {
+        let n = event_dates.len();
+        let block_start = |i: usize| match i {
+            0 => NaiveDate::MIN,
+            _ => {
+                let gap = (event_dates[i] - event_dates[i-1]).num_days();
+                event_dates[i-1] + Duration::days(gap / 2 + 1)
+            },
+        };
+        let block_end = |i: usize| if i == n { NaiveDate::MAX } else { block_start(i) - Duration::days(1) };
+        let mut folds: Vec<Fold> = Vec::new();
+        let mut test_lo = self.train_events;
+        while test_lo + self.test_events <= n
+        {
+            let train_lo = if self.anchored { 0 } else { test_lo - self.train_events };
+            let test_hi = test_lo + self.test_events;
+            folds.push(Fold {
+                train: block_start(train_lo)..=block_end(test_lo),
+                test: block_start(test_lo)..=block_end(test_hi),
+                train_events: event_dates[train_lo]..=event_dates[test_lo-1],
+                test_events: event_dates[test_lo]..=event_dates[test_hi-1],
+            });
+            test_lo = test_hi;
+        }
+        folds
+    }
+}
+
+/// A cell selected in one fold's training block and its metrics over the test block
+pub struct FoldResult
+{
+    pub fold: usize,
+    pub train_events: RangeInclusive<NaiveDate>,
+    pub test_events: RangeInclusive<NaiveDate>,
+    /// 1 for the best in-sample cell
+    pub rank: usize,
+    pub in_sample: StrategyResult,
+    pub in_sample_score: f64,
+    pub metrics: Vec<f64>,
+    pub n_obs: usize,
+}
+
+impl FieldsToStrings for FoldResult
+{
+    fn fields_to_strings(&self) -> Vec<String>
+    {
+        let r = &self.in_sample;
+        let mut v = vec![self.fold.to_string(), self.train_events.start().to_string(),
+                         self.train_events.end().to_string(), self.test_events.start().to_string(),
+                         self.test_events.end().to_string(), self.rank.to_string(), r.interval.to_string(),
+                         r.start_time.to_string(), r.end_time.to_string(), r.direction.to_string(),
+                         r.exit_rule.to_string(), self.in_sample_score.to_string()];
+        v.extend(self.metrics.iter().map(|x| x.to_string()));
+        v.push(self.n_obs.to_string());
+        v
+    }
+}
+
+impl FieldsToValues for FoldResult
+{
+    fn field_types(n: usize) -> Vec<FieldType>
+    {
+        use FieldType::*;
+        let mut leading = vec![Int, Date, Date, Date, Date, Int];
+        leading.extend(CELL_KEY_TYPES);
+        leading.push(Float);
+        metric_field_types(&leading, n)
+    }
+
+    fn fields_to_values(&self) -> Vec<FieldValue>
+    {
+        let r = &self.in_sample;
+        let mut v: Vec<FieldValue> = vec![self.fold.into(), (*self.train_events.start()).into(),
+                                          (*self.train_events.end()).into(), (*self.test_events.start()).into(),
+                                          (*self.test_events.end()).into(), self.rank.into(), r.interval.into(),
+                                          r.start_time.to_string().into(), r.end_time.to_string().into(),
+                                          r.direction.to_string().into(), r.exit_rule.to_string().into(),
+                                          self.in_sample_score.into()];
+        v.extend(self.metrics.iter().map(|&x| x.into()));
+        v.push(self.n_obs.into());
+        v
+    }
+}
+
+/// Test block trades of every fold's cell at one rank, stitched in order
+pub struct OutOfSampleResult
+{
+    pub rank: usize,
+    pub n_folds: usize,
+    pub metrics: Vec<f64>,
+    pub n_obs: usize,
+}
+
+impl FieldsToStrings for OutOfSampleResult
+{
+    fn fields_to_strings(&self) -> Vec<String>
+    {
+        let mut v = vec![self.rank.to_string(), self.n_folds.to_string()];
+        v.extend(self.metrics.iter().map(|x| x.to_string()));
+        v.push(self.n_obs.to_string());
+        v
+    }
+}
+
+impl FieldsToValues for OutOfSampleResult
+{
+    fn field_types(n: usize) -> Vec<FieldType>
+    {
+        metric_field_types(&[FieldType::Int, FieldType::Int], n)
+    }
+
+    fn fields_to_values(&self) -> Vec<FieldValue>
+    {
+        let mut v: Vec<FieldValue> = vec![self.rank.into(), self.n_folds.into()];
+        v.extend(self.metrics.iter().map(|&x| x.into()));
+        v.push(self.n_obs.into());
+        v
+    }
+}
+
+pub struct WalkForwardReport
+{
+    pub folds: Vec<FoldResult>,
+    pub out_of_sample: Vec<OutOfSampleResult>,
+}
+
+impl WalkForwardReport
+{
+    /// CSV header for `FoldResult` rows
+    pub fn fold_field_names(spec: &StrategySpec, config: &WalkForwardConfig) -> Vec<String>
+    {
+        let mut v: Vec<String> = ["fold", "train first event", "train last event", "test first event",
+                                  "test last event", "rank", "interval", "start time", "end time", "direction",
+                                  "exit rule"].iter().map(|x| x.to_string()).collect();
+        v.push(format!("in sample {}", config.select_by.replace('_', " ")));
+        v.extend(spec.metrics.metric_names(spec.costs.is_some()));
+        v.push("n obs".to_owned());
+        v
+    }
+
+    /// CSV header for `OutOfSampleResult` rows
+    pub fn out_of_sample_field_names(spec: &StrategySpec) -> Vec<String>
+    {
+        let mut v: Vec<String> = vec!["rank".to_owned(), "folds".to_owned()];
+        v.extend(spec.metrics.metric_names(spec.costs.is_some()));
+        v.push("n obs".to_owned());
+        v
+    }
+}
+
+/// Runs every fold over `event_dates`, sweeping each training block across the rayon thread pool
+pub fn walk_forward(engine: &WindowEngine, interval_rng: &[u64], start_time_rng: &[CellTime], spec: &StrategySpec,
+                    config: &WalkForwardConfig, event_dates: &[NaiveDate]) -> Result<WalkForwardReport, Box<dyn Error>>
+{
+    let select = spec.metrics.column(&config.select_by, spec.costs.is_some())
+        .ok_or_else(|| SimpleError::new(format!("walk_forward select_by {} is not a reported metric", config.select_by)))?;
+    let folds = config.folds(event_dates);
+    if folds.is_empty() {
+        return Err(Box::new(SimpleError::new(format!(
+            "{} events are too few for {} training and {} test events", event_dates.len(),
+            config.train_events, config.test_events))))
+    }
+
+    let mut fold_results: Vec<FoldResult> = Vec::new();
+    let mut stitched: Vec<(Observations, Observations, usize)> = (0..config.top_n)
+        .map(|_| (Observations::default(), Observations::default(), 0))
+        .collect();
+    for (k, fold) in folds.iter().enumerate()
+    {
+        let mut train: Vec<StrategyResult> = interval_rng.par_iter()
+            .flat_map_iter(|&interval| start_time_rng.iter()
+                .flat_map(move |start_time| analyse_cell(engine, interval, start_time, spec, &fold.train)))
+            .collect();
+        train.retain(|r| !r.metrics[select].is_nan());
+        train.sort_by(|a, b| b.metrics[select].total_cmp(&a.metrics[select]));
+        info!("Fold {}: {} cells trained on events {} to {}", k + 1, train.len(), fold.train_events.start(),
+              fold.train_events.end());
+
+        for (rank, in_sample) in train.into_iter().take(config.top_n).enumerate()
+        {
+            let (obs, net_obs) = cell_observations(engine, &in_sample.start_time, &in_sample.end_time,
+                                                   &[in_sample.direction], &[in_sample.exit_rule], spec,
+                                                   &fold.test).remove(0);
+            let (all_obs, all_net_obs, n_folds) = &mut stitched[rank];
+            all_obs.append(&obs);
+            all_net_obs.append(&net_obs);
+            *n_folds += 1;
+            fold_results.push(FoldResult {
+                fold: k + 1,
+                train_events: fold.train_events.clone(),
+                test_events: fold.test_events.clone(),
+                rank: rank + 1,
+                in_sample_score: in_sample.metrics[select],
+                in_sample,
+                metrics: spec.compute_metrics(&obs, &net_obs),
+                n_obs: obs.len(),
+            });
+        }
+    }
+
+    let out_of_sample = stitched.iter().enumerate()
+        .filter(|(_, (_, _, n_folds))| *n_folds > 0)
+        .map(|(rank, (obs, net_obs, n_folds))| OutOfSampleResult {
+            rank: rank + 1,
+            n_folds: *n_folds,
+            metrics: spec.compute_metrics(obs, net_obs),
+            n_obs: obs.len(),
+        })
+        .collect();
+    Ok(WalkForwardReport { folds: fold_results, out_of_sample })
+}
@@ -0,0 +1,227 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use itertools::Either;
+use crate::bars::BarSeries;
+use crate::conditions::ContextCondition;
+
+pub const MINS_IN_DAY: usize = 60*24;
+const NO_BAR: u32 = u32::MAX;
+
+/// When a cell's windows open or close: a clock time on every day, or minutes relative to each of
+/// the event's releases, so releases at different clock times line up
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CellTime
+{
+    Clock(NaiveTime),
+    Release(i64),
+}
+
+impl fmt::Display for CellTime
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self {
+            CellTime::Clock(t) => write!(f, "{}", t),
+            CellTime::Release(m) => write!(f, "T{:+}", m),
+        }
+    }
+}
+
+impl CellTime
+{
+    /// Minute of the day for a release at `release_minute`
+    fn minute(&self, release_minute: i64) -> i64
+    {
+        match self {
+            CellTime::Clock(t) => minute_of_day(t) as i64,
+            CellTime::Release(m) => release_minute + m,
+        }
+    }
+}
+
+/// Pre-indexed bar series answering "which bars open and close a window on each day" by direct
+/// lookup. Bars are indexed by (date, minute of day) once, so each interval/start time pair costs
+/// one lookup per trading day instead of rescanning the whole series. Windows never span days.
+pub struct WindowEngine
+{
+    pub bars: BarSeries,
+    days: Vec<NaiveDate>,
+    /// Row of the bar stamped exactly at each minute of each day, `NO_BAR` where there is none
+    minute_index: Vec<u32>,
+    /// Days with at least one bar satisfying the context condition
+    active_days: Vec<usize>,
+    context_condition: Vec<bool>,
+    /// Day and minute of day of each event release within the bars, in order
+    releases: Vec<(usize, i64)>,
+    low_table: SparseTable,
+    high_table: SparseTable,
+}
+
+impl WindowEngine
+{
+    /// `bars` must be sorted by time. The context condition must hold on both the entry and exit
+    /// bar of a window.
+    pub fn new(bars: BarSeries, condition: &dyn ContextCondition) -> Self
+    {
+        let context_condition = condition.evaluate(&bars);
+
+        let days: Vec<NaiveDate> = bars.days().to_vec();
+        let mut minute_index: Vec<u32> = vec![NO_BAR; days.len() * MINS_IN_DAY];
+        let mut active_days: Vec<usize> = Vec::new();
+        let mut max_day_len = 0_usize;
+        for d in 0..days.len()
+        {
+            let rows = bars.day_range(d);
+            max_day_len = max_day_len.max(rows.len());
+            if context_condition[rows.clone()].iter().any(|&x| x)
+            {
+                active_days.push(d);
+            }
+            for i in rows
+            {
+                let dt = bars.datetime(i);
+                if dt.second() == 0
+                {
+                    minute_index[d*MINS_IN_DAY + minute_of_day(&dt.time())] = i as u32;
+                }
+            }
+        }
+
+        let low_table = SparseTable::new(bars.low(), max_day_len, f64::min);
+        let high_table = SparseTable::new(bars.high(), max_day_len, f64::max);
+        Self { bars, days, minute_index, active_days, context_condition, releases: Vec::new(), low_table, high_table }
+    }
+
+    /// Release timestamps that `CellTime::Release` windows are measured from. Releases on days
+    /// without bars are ignored.
+    pub fn with_releases(mut self, releases: &[NaiveDateTime]) -> Self
+    {
+        self.releases = releases.iter()
+            .filter_map(|dt| Some((self.days.binary_search(&dt.date()).ok()?, minute_of_day(&dt.time()) as i64)))
+            .collect();
+        self.releases.sort();
+        self.releases.dedup();
+        self
+    }
+
+    pub fn days(&self) -> &[NaiveDate]
+    {
+        &self.days
+    }
+
+    /// Row of the bar at `time` on the `day`th day, if there is one
+    pub fn bar_at(&self, day: usize, time: &NaiveTime) -> Option<usize>
+    {
+        self.bar_at_minute(day, minute_of_day(time) as i64)
+    }
+
+    /// Row of the bar at `minute` of the `day`th day, if there is one
+    fn bar_at_minute(&self, day: usize, minute: i64) -> Option<usize>
+    {
+        if !(0..MINS_IN_DAY as i64).contains(&minute) { return None }
+        match self.minute_index[day*MINS_IN_DAY + minute as usize]
+        {
+            NO_BAR => None,
+            i => Some(i as usize),
+        }
+    }
+
+    /// Entry and exit rows of every window opened at `start` and closed at `end` on the same day, in
+    /// chronological order. Clock windows open once a day, windows with either end relative to a
+    /// release once per release.
+    pub fn windows<'a>(&'a self, start: &'a CellTime, end: &'a CellTime)
+                       -> impl Iterator<Item = (usize, usize)> + 'a
+    {
+        self.windows_between(start, end, &all_days())
+    }
+
+    /// `windows` restricted to the days in `days`
+    pub fn windows_between<'a>(&'a self, start: &'a CellTime, end: &'a CellTime,
+                               days: &RangeInclusive<NaiveDate>) -> impl Iterator<Item = (usize, usize)> + 'a
+    {
+        let window = move |d: usize, release_minute: i64| {
+            let entry_ix = self.bar_at_minute(d, start.minute(release_minute))?;
+            let exit_ix = self.bar_at_minute(d, end.minute(release_minute))?;
+            if exit_ix > entry_ix && self.context_condition[entry_ix] && self.context_condition[exit_ix] {
+                Some((entry_ix, exit_ix))
+            } else {
+                None
+            }
+        };
+        match (start, end) {
+            (CellTime::Clock(_), CellTime::Clock(_)) => {
+                let lo = self.active_days.partition_point(|&d| self.days[d] < *days.start());
+                let hi = self.active_days.partition_point(|&d| self.days[d] <= *days.end());
+                Either::Left(self.active_days[lo..hi.max(lo)].iter().filter_map(move |&d| window(d, 0)))
+            },
+            _ => {
+                let lo = self.releases.partition_point(|&(d, _)| self.days[d] < *days.start());
+                let hi = self.releases.partition_point(|&(d, _)| self.days[d] <= *days.end());
+                Either::Right(self.releases[lo..hi.max(lo)].iter().filter_map(move |&(d, m)| window(d, m)))
+            },
+        }
+    }
+
+    /// Lowest low of bars `lo..=hi`, in constant time for ranges within one day
+    pub fn range_low(&self, lo: usize, hi: usize) -> f64
+    {
+        self.low_table.query(lo, hi)
+    }
+
+    /// Highest high of bars `lo..=hi`, in constant time for ranges within one day
+    pub fn range_high(&self, lo: usize, hi: usize) -> f64
+    {
+        self.high_table.query(lo, hi)
+    }
+}
+
+/// Every representable date, for unrestricted `windows_between` calls
+pub fn all_days() -> RangeInclusive<NaiveDate>
+{
+    NaiveDate::MIN..=NaiveDate::MAX
+}
+
+pub fn minute_of_day(time: &NaiveTime) -> usize
+{
+    (time.hour()*60 + time.minute()) as usize
+}
+
+/// Range min/max lookup table, with levels up to the longest range that will be queried
+struct SparseTable
+{
+    levels: Vec<Vec<f64>>,
+    op: fn(f64, f64) -> f64,
+}
+
+impl SparseTable
+{
+    fn new(values: &[f64], max_range: usize, op: fn(f64, f64) -> f64) -> Self
+    {
+        let mut levels: Vec<Vec<f64>> = vec![values.to_vec()];
+        let mut width = 1_usize;
+        while width*2 <= max_range
+        {
+            let prev = &levels[levels.len()-1];
+            let next: Vec<f64> = (0..prev.len().saturating_sub(width))
+                .map(|i| op(prev[i], prev[i+width]))
+                .collect();
+            levels.push(next);
+            width *= 2;
+        }
+        Self { levels, op }
+    }
+
+    fn query(&self, lo: usize, hi: usize) -> f64
+    {
+        let len = hi - lo + 1;
+        let k = ((usize::BITS - len.leading_zeros() - 1) as usize).min(self.levels.len() - 1);
+        let width = 1 << k;
+        if width*2 < len
+        {
+            // Longer than any indexed range, fall back to a scan
+            return self.levels[0][lo..=hi].iter().cloned().reduce(self.op).unwrap()
+        }
+        (self.op)(self.levels[k][lo], self.levels[k][hi + 1 - width])
+    }
+}